@@ -1,10 +1,12 @@
 use include_wasm_rs::build_wasm;
 
 macro_rules! module {
-    ($path:expr) => {
+    ($path:expr $(, $feature:ident)*) => {
         build_wasm! {
             path: $path,
             features: [bulk_memory],
+            // Added to the features above
+            features: [$($feature),*],
             env: Env {
                 MY_ENV_VAR: 12,
             },
@@ -14,7 +16,7 @@ macro_rules! module {
 }
 
 fn main() {
-    let module = module!("wasm_module");
+    let module = module!("wasm_module", sign_ext);
 
     println!("wasm bytes: {module:?}");
 }
@@ -0,0 +1,7 @@
+use include_wasm_rs::build_wat;
+
+fn main() {
+    let module = build_wat!("./wat_module/module.wat");
+
+    println!("wasm bytes: {module:?}");
+}
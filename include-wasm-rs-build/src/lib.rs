@@ -0,0 +1,3576 @@
+//! The build logic behind the `build_wasm!` macro of `include-wasm-rs`, for building WebAssembly
+//! modules from build scripts.
+//!
+//! ```ignore
+//! // build.rs
+//! use include_wasm_rs_build::{build, module_files, BuildOptions};
+//!
+//! fn main() {
+//!     let options = BuildOptions {
+//!         module_dir: "path/to/module".into(),
+//!         release: true,
+//!         ..BuildOptions::default()
+//!     };
+//!     let module = build(&options).unwrap();
+//!
+//!     for file in module_files(&options) {
+//!         println!("cargo:rerun-if-changed={file}");
+//!     }
+//!     println!("cargo:rustc-env=WASM_MODULE={}", module.display());
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+};
+
+/// Allows unstable flags on stable toolchains, so decides which toolchain the module is built with.
+pub const RUSTC_BOOTSTRAP: &str = "RUSTC_BOOTSTRAP";
+
+/// The target that modules are built for, unless given another.
+pub const DEFAULT_TARGET: &str = "wasm32-unknown-unknown";
+
+/// Moves the host's build output, so decides where modules are built unless given a `target_dir`.
+pub const CARGO_TARGET_DIR: &str = "CARGO_TARGET_DIR";
+
+/// The WebAssembly proposals enabled while building a module.
+#[derive(Debug, Default, Clone)]
+pub struct TargetFeatures {
+    /// The `atomics` proposal.
+    pub atomics: bool,
+    /// The `bulk-memory` proposal.
+    pub bulk_memory: bool,
+    /// The `mutable-globals` proposal.
+    pub mutable_globals: bool,
+    /// The `sign-ext` proposal.
+    pub sign_ext: bool,
+    /// The `nontrapping-fptoint` proposal.
+    pub nontrapping_fptoint: bool,
+    /// The `multivalue` proposal.
+    pub multivalue: bool,
+    /// The `reference-types` proposal.
+    pub reference_types: bool,
+    /// The `tail-call` proposal.
+    pub tail_call: bool,
+    /// The `memory64` proposal, which requires the `wasm64-unknown-unknown` target.
+    pub memory64: bool,
+    /// The proposals explicitly disabled, by the names taken by [`TargetFeatures::enable`], which are disabled
+    /// even if enabled above.
+    pub disabled: Vec<String>,
+}
+
+impl TargetFeatures {
+    /// The features enabled by a named preset. Presets are versioned by year, so that the features they
+    /// enable never change, while the unversioned names refer to the latest version.
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            // The proposals enabled by default in all major browsers as of 2024
+            "web" | "web-2024" => Some(Self {
+                bulk_memory: true,
+                mutable_globals: true,
+                sign_ext: true,
+                nontrapping_fptoint: true,
+                multivalue: true,
+                reference_types: true,
+                ..Self::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Every proposal other than `memory64`, which needs its own target, for checking that a module builds
+    /// with the broadest set of features.
+    pub fn all() -> Self {
+        Self {
+            atomics: true,
+            bulk_memory: true,
+            mutable_globals: true,
+            sign_ext: true,
+            nontrapping_fptoint: true,
+            multivalue: true,
+            reference_types: true,
+            tail_call: true,
+            memory64: false,
+            disabled: Vec::new(),
+        }
+    }
+
+    /// Enables every feature enabled in `other`, and disables every feature disabled in `other`.
+    pub fn merge(&mut self, other: Self) {
+        self.atomics |= other.atomics;
+        self.bulk_memory |= other.bulk_memory;
+        self.mutable_globals |= other.mutable_globals;
+        self.sign_ext |= other.sign_ext;
+        self.nontrapping_fptoint |= other.nontrapping_fptoint;
+        self.multivalue |= other.multivalue;
+        self.reference_types |= other.reference_types;
+        self.tail_call |= other.tail_call;
+        self.memory64 |= other.memory64;
+        for name in other.disabled {
+            self.disable(&name);
+        }
+    }
+
+    /// Enables the feature with the given name, using underscores rather than hyphens, or every feature
+    /// for `all`, returning false if there is no such feature.
+    pub fn enable(&mut self, name: &str) -> bool {
+        match name {
+            "atomics" => self.atomics = true,
+            "bulk_memory" => self.bulk_memory = true,
+            "mutable_globals" => self.mutable_globals = true,
+            "sign_ext" => self.sign_ext = true,
+            "nontrapping_fptoint" => self.nontrapping_fptoint = true,
+            "multivalue" => self.multivalue = true,
+            "reference_types" => self.reference_types = true,
+            "tail_call" => self.tail_call = true,
+            "memory64" => self.memory64 = true,
+            "all" => self.merge(Self::all()),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Disables the feature with the given name, using underscores rather than hyphens, so that it is turned
+    /// off even if the toolchain enables it by default. Returns false if there is no such feature.
+    pub fn disable(&mut self, name: &str) -> bool {
+        if name == "all" || !Self::default().enable(name) {
+            return false;
+        }
+        if !self.is_disabled(name) {
+            self.disabled.push(name.to_owned());
+        }
+        true
+    }
+
+    /// Checks if the feature with the given name, using underscores rather than hyphens, is disabled.
+    fn is_disabled(&self, name: &str) -> bool {
+        self.disabled.iter().any(|disabled| disabled == name)
+    }
+
+    /// Checks that the enabled features can be used together on the given target, giving an error
+    /// describing the first combination known not to work. This is conservative, so passing doesn't mean
+    /// that a module will build, and custom target spec files are only checked for combinations that
+    /// never work.
+    pub fn check(&self, target: &str) -> Result<(), String> {
+        let enabled = |enabled: bool, name| enabled && !self.is_disabled(name);
+        let atomics = enabled(self.atomics, "atomics");
+
+        // Each feature, the feature it requires, and why
+        let requirements = [(
+            atomics,
+            enabled(self.bulk_memory, "bulk_memory"),
+            "atomics",
+            "bulk_memory",
+            "shared memories can only be initialised with passive data segments",
+        )];
+        for (enabled, required_enabled, feature, required, reason) in requirements {
+            if enabled && !required_enabled {
+                return Err(format!(
+                    "the `{feature}` feature requires `{required}` on `{target}`, since {reason} - \
+                    try enabling `{required}`, or set `skip_feature_checks: true`"
+                ));
+            }
+        }
+
+        // Each feature, whether the target lacks what it needs, what that is, and a target that has it
+        let custom = is_custom_target(target);
+        let requirements = [
+            (
+                atomics,
+                "atomics",
+                matches!(target, "wasm32-wasi" | "wasm32-wasip1" | "wasm32-wasip2"),
+                "a target with threads",
+                "wasm32-wasip1-threads",
+            ),
+            // A custom target's pointer width isn't known without parsing its spec
+            (
+                enabled(self.memory64, "memory64"),
+                "memory64",
+                !custom && !target.starts_with("wasm64"),
+                "a 64-bit target",
+                "wasm64-unknown-unknown",
+            ),
+        ];
+        for (enabled, feature, unsupported, requirement, supported) in requirements {
+            if enabled && unsupported {
+                return Err(format!(
+                    "the `{feature}` feature requires {requirement}, but the target is `{target}` - \
+                    try setting `target: \"{supported}\"`, or set `skip_feature_checks: true`"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the features requested by a module in the `[package.metadata.wasm]` table of its manifest,
+    /// as a `features` list and an optional `preset`.
+    fn from_manifest(manifest: &toml::Table, module_dir: &Path) -> Result<Self, String> {
+        let mut res = Self::default();
+
+        let Some(wasm) = manifest
+            .get("package")
+            .and_then(|package| package.get("metadata"))
+            .and_then(|metadata| metadata.get("wasm"))
+        else {
+            return Ok(res);
+        };
+
+        let as_name = |value: &toml::Value| {
+            value.as_str().map(str::to_owned).ok_or_else(|| {
+                format!(
+                    "expected strings in `package.metadata.wasm` of module `{}`",
+                    module_dir.display()
+                )
+            })
+        };
+
+        if let Some(preset) = wasm.get("preset") {
+            let preset = as_name(preset)?;
+            match Self::preset(&preset) {
+                Some(features) => res.merge(features),
+                None => {
+                    return Err(format!(
+                        "unknown preset `{preset}` in `package.metadata.wasm` of module `{}`",
+                        module_dir.display()
+                    ))
+                }
+            }
+        }
+        for feature in wasm
+            .get("features")
+            .and_then(toml::Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let feature = as_name(feature)?;
+            if !res.enable(&feature.replace('-', "_")) {
+                return Err(format!(
+                    "unknown feature `{feature}` in `package.metadata.wasm` of module `{}`",
+                    module_dir.display()
+                ));
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+/// Where the bytes written over a data segment by [`BuildOptions::inject_data`] come from.
+#[derive(Debug, Clone)]
+pub enum InjectSource {
+    /// The contents of an environment variable of the building process.
+    Env(String),
+    /// The contents of a file.
+    File(PathBuf),
+}
+
+impl InjectSource {
+    /// Reads the bytes to inject.
+    fn read(&self) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Env(name) => std::env::var(name).map(String::into_bytes).map_err(|e| {
+                format!("failed to read environment variable `{name}` to inject: {e}")
+            }),
+            Self::File(path) => std::fs::read(path)
+                .map_err(|e| format!("failed to read file `{}` to inject: {e}", path.display())),
+        }
+    }
+}
+
+/// Bytes to write over the start of a data segment of a built module.
+#[derive(Debug, Clone)]
+pub struct InjectData {
+    /// The index of the data segment.
+    pub segment: u32,
+    /// Where the bytes come from.
+    pub source: InjectSource,
+}
+
+/// How to build a module. Each option corresponds to the argument of the same name of `build_wasm!`, where
+/// it is described in more detail.
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    /// The module's directory.
+    pub module_dir: PathBuf,
+    /// The proposals to enable.
+    pub features: TargetFeatures,
+    /// Environment variables to set while building, with `RUSTFLAGS` and `CARGO_ENCODED_RUSTFLAGS` merged
+    /// into the generated flags.
+    pub env_vars: Vec<(String, String)>,
+    /// Builds in release mode rather than debug.
+    pub release: bool,
+    /// Sets `-C opt-level`.
+    pub opt_level: Option<String>,
+    /// Sets `-C lto`.
+    pub lto: Option<String>,
+    /// Sets `-C codegen-units`.
+    pub codegen_units: Option<u32>,
+    /// Appended verbatim to the `-C target-feature` list.
+    pub raw_target_features: String,
+    /// Sets `-C panic`.
+    pub panic: Option<String>,
+    /// Defaults `opt_level`, `lto`, `codegen_units` and `panic` to the settings giving the smallest module, and
+    /// strips debug info.
+    pub size_optimized: bool,
+    /// The crates passed to `-Z build-std`, defaulting to the panic crate matching `panic`, and `std`. For WASI targets
+    /// without a `panic`, defaults to `std` alone.
+    pub build_std: Option<Vec<String>>,
+    /// The features of the standard library passed to `-Z build-std-features`.
+    pub build_std_features: Vec<String>,
+    /// Passed to rustc as `-Z` flags.
+    pub z_flags: Vec<String>,
+    /// Passed to cargo as `--config` overrides, each given as `key=value`.
+    pub config: Vec<String>,
+    /// Sets `-C linker`.
+    pub linker: Option<PathBuf>,
+    /// Passed to rustc as `-C link-arg` flags.
+    pub link_args: Vec<String>,
+    /// Adds `-D warnings`.
+    pub deny_warnings: bool,
+    /// Adds `--cfg=web_sys_unstable_apis`, enabling `web-sys`'s unstable APIs.
+    pub web_sys_unstable: bool,
+    /// The size given to `--max-memory` when `atomics` is enabled, in bytes.
+    pub shared_memory_max: Option<u64>,
+    /// Deletes the output directory and rebuilds once if more than one module is found in it.
+    pub clean_stale: bool,
+    /// Deletes the contents of the module's target directory before building. Can't be used with `target_dir`
+    /// or `share_std`.
+    pub clean: bool,
+    /// Uses the first module found in the output directory, by sorted filename, rather than failing if
+    /// there are more than one.
+    pub allow_multiple: bool,
+    /// Builds only the binary target with this name, with `--bin`, rather than every target.
+    pub bin: Option<String>,
+    /// The pattern used to find the built module in the output directory. Unless given, files in cargo's
+    /// intermediate directories, e.g. `deps`, are never found.
+    pub output_glob: Option<String>,
+    /// A tool to run on a copy of the built module.
+    pub post_process: Option<PathBuf>,
+    /// Bytes to write over the start of a data segment of the built module.
+    pub inject_data: Option<InjectData>,
+    /// Adds a custom section describing the build to the module.
+    pub metadata_section: bool,
+    /// Merges the rustflags from the module's `.cargo/config.toml` files into the generated flags.
+    pub merge_config_rustflags: bool,
+    /// Removes custom sections that vary between machines from the module.
+    pub reproducible: bool,
+    /// Allows cargo to compile the module incrementally, as it does by default in debug builds.
+    pub incremental: bool,
+    /// Also enables the features requested in the module's `[package.metadata.wasm]` table.
+    pub auto_features: bool,
+    /// Skips the checks made on the module's manifest before building.
+    pub skip_manifest_checks: bool,
+    /// Skips checking that the enabled features can be used together on the target.
+    pub skip_feature_checks: bool,
+    /// Checks that each target feature is known to the toolchain, by asking `rustc` for the target's features.
+    pub strict_features: bool,
+    /// Turns the module into a component with `wasm-tools component new`.
+    pub component: bool,
+    /// The WASI adapter given to `wasm-tools component new`.
+    pub adapter: Option<PathBuf>,
+    /// A script that rewrites the arguments given to cargo, given them as a JSON array of strings on stdin
+    /// and printing the new arguments in the same form. Skipped if the script doesn't exist.
+    pub command_template: Option<PathBuf>,
+    /// Passed to cargo as `--jobs`.
+    pub jobs: Option<u32>,
+    /// Silences cargo's progress output.
+    pub quiet: bool,
+    /// Skips running cargo if the build's inputs haven't changed since the last build.
+    pub cache: bool,
+    /// Treats CRLF and LF line endings in the module's text files as the same when deciding if the
+    /// module has changed since its last build.
+    pub normalize_line_endings: bool,
+    /// Glob patterns, relative to the module's directory, of files that changes to shouldn't cause a rebuild.
+    pub track_ignore: Vec<String>,
+    /// Overrides the target directory derived from the build configuration.
+    pub target_dir: Option<PathBuf>,
+    /// Builds in a target directory shared by every module built with the same configuration, so that the
+    /// standard library built by `-Z build-std` is reused between them.
+    pub share_std: bool,
+    /// The toolchain to build with, instead of `nightly`.
+    pub toolchain: Option<String>,
+    /// The target to build for, instead of [`DEFAULT_TARGET`]. A path ending in `.json` names a custom target
+    /// spec file, which requires `build_std`.
+    pub target: Option<String>,
+    /// Given to cargo as `--manifest-path`, to build the crate with this `Cargo.toml` while cargo still runs in
+    /// `module_dir`. Relative to `module_dir` unless absolute.
+    pub manifest_path: Option<PathBuf>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            module_dir: PathBuf::new(),
+            features: TargetFeatures::default(),
+            env_vars: Vec::new(),
+            release: false,
+            opt_level: None,
+            lto: None,
+            codegen_units: None,
+            raw_target_features: String::new(),
+            panic: None,
+            size_optimized: false,
+            build_std: None,
+            build_std_features: Vec::new(),
+            z_flags: Vec::new(),
+            config: Vec::new(),
+            linker: None,
+            link_args: Vec::new(),
+            deny_warnings: false,
+            web_sys_unstable: true,
+            shared_memory_max: None,
+            clean_stale: false,
+            clean: false,
+            allow_multiple: false,
+            bin: None,
+            output_glob: None,
+            post_process: None,
+            inject_data: None,
+            metadata_section: false,
+            merge_config_rustflags: false,
+            reproducible: false,
+            incremental: true,
+            auto_features: false,
+            skip_manifest_checks: false,
+            skip_feature_checks: false,
+            strict_features: false,
+            component: false,
+            adapter: None,
+            command_template: None,
+            jobs: None,
+            quiet: false,
+            cache: true,
+            normalize_line_endings: true,
+            track_ignore: Vec::new(),
+            target_dir: None,
+            share_std: false,
+            toolchain: None,
+            target: None,
+            manifest_path: None,
+        }
+    }
+}
+
+impl BuildOptions {
+    /// The directory of the crate being built, which is `module_dir` unless `manifest_path` points elsewhere.
+    pub fn crate_dir(&self) -> PathBuf {
+        match &self.manifest_path {
+            Some(manifest_path) => self
+                .module_dir
+                .join(manifest_path)
+                .parent()
+                .map_or_else(|| self.module_dir.clone(), Path::to_owned),
+            None => self.module_dir.clone(),
+        }
+    }
+}
+
+impl Display for TargetFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let features = [
+            (self.atomics, "atomics"),
+            (self.bulk_memory, "bulk_memory"),
+            (self.mutable_globals, "mutable_globals"),
+            (self.sign_ext, "sign_ext"),
+            (self.nontrapping_fptoint, "nontrapping_fptoint"),
+            (self.multivalue, "multivalue"),
+            (self.reference_types, "reference_types"),
+            (self.tail_call, "tail_call"),
+            (self.memory64, "memory64"),
+        ];
+        // Disabling takes precedence, so that a feature enabled by e.g. a preset can still be turned off
+        for (enabled, name) in features {
+            if self.is_disabled(name) {
+                write!(f, "-{},", name.replace('_', "-"))?
+            } else if enabled {
+                write!(f, "+{},", name.replace('_', "-"))?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Enables logging of how modules are built to stderr when set to `debug`.
+pub const INCLUDE_WASM_RS_LOG: &str = "INCLUDE_WASM_RS_LOG";
+
+/// Whether logging is enabled by [`INCLUDE_WASM_RS_LOG`].
+fn debug_logging() -> bool {
+    std::env::var_os(INCLUDE_WASM_RS_LOG).is_some_and(|level| level == "debug")
+}
+
+/// Prints a message to stderr if logging is enabled by [`INCLUDE_WASM_RS_LOG`].
+pub fn debug_log(message: impl Display) {
+    if debug_logging() {
+        eprintln!("[include-wasm-rs] {message}");
+    }
+}
+
+/// Escapes a string as a JSON string literal, including the surrounding quotes.
+pub fn json_string(value: &str) -> String {
+    let mut res = String::with_capacity(value.len() + 2);
+    res.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Reads the rustflags that cargo would take from `.cargo/config.toml` files when building the module for
+/// the given target, if `RUSTFLAGS` wasn't set.
+///
+/// Like cargo, config files in the module directory and all of its ancestors are read, with flags from
+/// deeper directories coming later, and `target.<triple>.rustflags` is preferred to `build.rustflags`.
+fn read_config_rustflags(module_dir: &Path, target: &str) -> Result<Vec<String>, String> {
+    let mut target_flags = Vec::new();
+    let mut build_flags = Vec::new();
+
+    // Avoid visiting directories twice through `.` components
+    let module_dir = module_dir
+        .canonicalize()
+        .unwrap_or_else(|_| module_dir.to_owned());
+    let mut ancestors = module_dir.ancestors().collect::<Vec<_>>();
+    ancestors.reverse();
+    for dir in ancestors {
+        for name in ["config.toml", "config"] {
+            let path = dir.join(".cargo").join(name);
+            let Ok(contents) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let config = contents
+                .parse::<toml::Table>()
+                .map_err(|e| format!("failed to parse `{}`: {e}", path.display()))?;
+
+            let flags_at = |table: Option<&toml::Value>| -> Result<Vec<String>, String> {
+                match table.and_then(|table| table.get("rustflags")) {
+                    None => Ok(Vec::new()),
+                    Some(toml::Value::String(flags)) => {
+                        Ok(flags.split_whitespace().map(str::to_owned).collect())
+                    }
+                    Some(toml::Value::Array(flags)) => flags
+                        .iter()
+                        .map(|flag| {
+                            flag.as_str().map(str::to_owned).ok_or_else(|| {
+                                format!("expected rustflags in `{}` to be strings", path.display())
+                            })
+                        })
+                        .collect(),
+                    Some(_) => Err(format!(
+                        "expected rustflags in `{}` to be a string or array",
+                        path.display()
+                    )),
+                }
+            };
+            target_flags.extend(flags_at(
+                config.get("target").and_then(|targets| targets.get(target)),
+            )?);
+            build_flags.extend(flags_at(config.get("build"))?);
+
+            // Cargo ignores `config` if `config.toml` exists
+            break;
+        }
+    }
+
+    if target_flags.is_empty() {
+        Ok(build_flags)
+    } else {
+        Ok(target_flags)
+    }
+}
+
+/// Splits any `-C target-feature` flags out of a list of rustc flags, returning the remaining flags,
+/// and the lists of features given in order.
+fn split_target_features<'a>(
+    rustflags: impl IntoIterator<Item = &'a str>,
+) -> (Vec<&'a str>, Vec<&'a str>) {
+    let mut flags = Vec::new();
+    let mut target_features = Vec::new();
+
+    let mut tokens = rustflags.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        if let Some(features) = token.strip_prefix("-Ctarget-feature=") {
+            target_features.push(features.trim_matches(','));
+            continue;
+        }
+        if token == "-C" || token == "--codegen" {
+            if let Some(features) = tokens
+                .peek()
+                .and_then(|next| next.strip_prefix("target-feature="))
+            {
+                target_features.push(features.trim_matches(','));
+                tokens.next();
+                continue;
+            }
+        }
+
+        flags.push(token);
+    }
+
+    (flags, target_features)
+}
+
+/// Whether a target is a custom target spec file, rather than a built-in target.
+fn is_custom_target(target: &str) -> bool {
+    target.ends_with(".json")
+}
+
+/// The name that cargo gives a target, e.g. for its output directory: the target itself, or for a custom
+/// target spec file, the file's stem.
+fn target_name(target: &str) -> &str {
+    if is_custom_target(target) {
+        Path::new(target)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(target)
+    } else {
+        target
+    }
+}
+
+/// The target features known to each toolchain and target that have been asked for, or `None` where they
+/// couldn't be found, so that `rustc` is run at most once for each.
+static KNOWN_TARGET_FEATURES: Mutex<BTreeMap<String, Option<Vec<String>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Asks `rustc` for the target features that the toolchain knows of for the target, giving `None` if they can't be
+/// found, e.g. because `rustc` couldn't be run or its output wasn't understood.
+fn known_target_features(toolchain: Option<&str>, target: &str) -> Option<Vec<String>> {
+    let key = format!("{}\n{target}", toolchain.unwrap_or("default"));
+    let mut known = KNOWN_TARGET_FEATURES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    known
+        .entry(key)
+        .or_insert_with(|| {
+            let mut command = Command::new("rustc");
+            if let Some(toolchain) = toolchain {
+                command.arg(format!("+{toolchain}"));
+            }
+            let output = command
+                .args(["--target", target, "--print", "target-features"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            // e.g. "    bulk-memory            - Enable bulk memory operations."
+            let features = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|line| line.starts_with(char::is_whitespace))
+                .filter_map(|line| line.split_once(" - "))
+                .filter_map(|(name, _)| Some(name.split_whitespace().next()?.to_owned()))
+                .collect::<Vec<_>>();
+            debug_log(format_args!(
+                "found {} target features known to `rustc` for `{target}`",
+                features.len()
+            ));
+            (!features.is_empty()).then_some(features)
+        })
+        .clone()
+}
+
+/// Maps the output of a failed cargo build to the message shown to the user, recognising common causes
+/// of failure and suggesting how to fix them.
+fn map_build_error(stdout: &[u8], stderr: &str, options: &BuildOptions, command: &str) -> String {
+    let toolchain = options.toolchain.as_deref().unwrap_or("nightly");
+    let target = options.target.as_deref().unwrap_or(DEFAULT_TARGET);
+    // e.g. "error: toolchain 'nightly-x86_64-unknown-linux-gnu' is not installed"
+    let hint = if stderr.contains("toolchain '") && stderr.contains("is not installed") {
+        format!(
+            "the `{toolchain}` toolchain is not installed - \
+            try running `rustup toolchain install {toolchain}`, \
+            as nightly is required to build the standard library for WebAssembly with `-Z build-std`"
+        )
+    } else if stderr.contains("can't find crate for `std`")
+        || stderr.contains("can't find crate for `core`")
+        || stderr.contains("target may not be installed")
+    {
+        if is_custom_target(target) {
+            format!(
+                "custom target `{target}` has no prebuilt standard library - \
+                give the crates to build with `build_std`"
+            )
+        } else {
+            format!(
+                "the `{target}` target is not installed - \
+                try running `rustup target add {target} --toolchain {toolchain}`"
+            )
+        }
+    } else if let Some(package) = stderr
+        .split("failed to run custom build command for `")
+        .nth(1)
+        .and_then(|rest| rest.split('`').next())
+    {
+        // e.g. "error: failed to run custom build command for `module v0.1.0 (/path/to/module)`"
+        format!("the build script of `{package}` failed - see its output below")
+    } else if stderr.contains("believes it's in a workspace when it's not") {
+        "the module is nested within another workspace without being a member of it - \
+        try adding an empty `[workspace]` table to the module's `Cargo.toml`"
+            .to_owned()
+    } else {
+        String::new()
+    };
+
+    // Anything on stdout that isn't one of cargo's JSON messages was printed by something else, such as a build script
+    let stdout = String::from_utf8_lossy(stdout);
+    let stdout = stdout
+        .lines()
+        .filter(|line| serde_json::from_str::<serde_json::Value>(line).is_err())
+        .collect::<Vec<&str>>();
+
+    // Output is kept verbatim, since indenting it mangles long errors
+    let mut message = format!(
+        "failed to build module `{}`: {hint}\nrunning `{command}`\n{stderr}",
+        options.module_dir.display(),
+    );
+    if !stdout.is_empty() {
+        message += &format!("\nstdout:\n{}", stdout.join("\n"));
+    }
+    message
+}
+
+/// Finds the `.wasm` file built for the crate from the JSON messages that cargo printed to stdout, if cargo reported one,
+/// only considering the binary target named `bin` if given.
+fn find_reported_artifact(
+    stdout: &[u8],
+    crate_dir: &Path,
+    bin: Option<&str>,
+) -> Result<Option<PathBuf>, String> {
+    let Ok(manifest_path) = crate_dir.join("Cargo.toml").canonicalize() else {
+        return Ok(None);
+    };
+
+    let mut outputs = Vec::new();
+    for line in String::from_utf8_lossy(stdout).lines() {
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if message["reason"] != "compiler-artifact" {
+            continue;
+        }
+
+        // Skip artifacts of dependencies, including the standard library
+        let is_module = message["manifest_path"]
+            .as_str()
+            .and_then(|path| Path::new(path).canonicalize().ok())
+            .is_some_and(|path| path == manifest_path);
+        if !is_module {
+            continue;
+        }
+        // Libraries that the binary depends on may also be built
+        if let Some(bin) = bin {
+            let target = &message["target"];
+            let is_bin = target["kind"]
+                .as_array()
+                .is_some_and(|kinds| kinds.iter().any(|kind| kind == "bin"));
+            if !is_bin || target["name"] != bin {
+                continue;
+            }
+        }
+
+        let filenames = message["filenames"].as_array().into_iter().flatten();
+        outputs.extend(
+            filenames
+                .filter_map(serde_json::Value::as_str)
+                .filter(|filename| filename.ends_with(".wasm"))
+                .map(PathBuf::from),
+        );
+    }
+
+    match outputs.as_slice() {
+        [] => Ok(None),
+        [output] => Ok(Some(output.clone())),
+        _ => Err(format!(
+            "cargo reported multiple `.wasm` files for module `{}`: {}",
+            crate_dir.display(),
+            outputs
+                .iter()
+                .map(|path| format!("`{}`", path.display()))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Copies the built module somewhere that it can be post-processed, so that post-processing is never
+/// applied twice to the same build output when cargo has nothing to rebuild.
+fn copy_for_processing(output: &Path, profile_output: &Path) -> Result<PathBuf, String> {
+    // Kept in a subdirectory so that it isn't mistaken for a build output
+    let processed_dir = profile_output.join("post_processed");
+    std::fs::create_dir_all(&processed_dir).map_err(|e| {
+        format!(
+            "failed to create post-processing directory `{}`: {e}",
+            processed_dir.display()
+        )
+    })?;
+    let processed = processed_dir.join(output.file_name().expect("output should be a file"));
+    std::fs::copy(output, &processed).map_err(|e| {
+        format!(
+            "failed to copy `{}` for post-processing: {e}",
+            output.display()
+        )
+    })?;
+
+    Ok(processed)
+}
+
+/// Turns a core module into a component with `wasm-tools component new`, using the given WASI adapter if any,
+/// returning the path of the component.
+fn make_component(
+    module: &Path,
+    adapter: Option<&Path>,
+    profile_output: &Path,
+) -> Result<PathBuf, String> {
+    // Kept in a subdirectory so that it isn't mistaken for a build output
+    let component_dir = profile_output.join("component");
+    std::fs::create_dir_all(&component_dir).map_err(|e| {
+        format!(
+            "failed to create component directory `{}`: {e}",
+            component_dir.display()
+        )
+    })?;
+    let component = component_dir.join(module.file_name().expect("module should be a file"));
+
+    let mut command = Command::new("wasm-tools");
+    command.args(["component", "new"]).arg(module);
+    if let Some(adapter) = adapter {
+        command.arg("--adapt").arg(adapter);
+    }
+    let out = command.arg("-o").arg(&component).output().map_err(|e| {
+        format!(
+            "failed to run `wasm-tools`: {e} - \
+            `component` requires the `wasm-tools` CLI, try running `cargo install wasm-tools`"
+        )
+    })?;
+    if !out.status.success() {
+        return Err(format!(
+            "`wasm-tools component new` failed with {}: \n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+
+    let bytes = std::fs::read(&component)
+        .map_err(|e| format!("failed to read component `{}`: {e}", component.display()))?;
+    if !wasmparser::Parser::is_component(&bytes) {
+        return Err(format!(
+            "`wasm-tools component new` did not produce a component at `{}`",
+            component.display()
+        ));
+    }
+
+    Ok(component)
+}
+
+/// Runs a command template script on the planned arguments of cargo, giving the arguments that it prints.
+fn apply_command_template(script: &Path, args: &[&str]) -> Result<Vec<String>, String> {
+    let input = format!(
+        "[{}]",
+        args.iter()
+            .map(|arg| json_string(arg))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+
+    let mut child = Command::new(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run command template `{}`: {e}", script.display()))?;
+    // Written from another thread, since a script printing more than a pipe holds before reading all of its
+    // input would otherwise wait on us forever. Closed once written, so that the script sees the end of its input.
+    let mut stdin = child.stdin.take().expect("stdin should be piped");
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let out = child
+        .wait_with_output()
+        .map_err(|e| format!("failed to run command template `{}`: {e}", script.display()))?;
+    let write = writer
+        .join()
+        .expect("writing to a command template shouldn't panic");
+    if !out.status.success() {
+        return Err(format!(
+            "command template `{}` failed with {}: \n{}",
+            script.display(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).replace('\n', "\n\t")
+        ));
+    }
+    write.map_err(|e| {
+        format!(
+            "failed to give arguments to command template `{}`: {e}",
+            script.display()
+        )
+    })?;
+
+    serde_json::from_slice::<Vec<String>>(&out.stdout).map_err(|e| {
+        format!(
+            "command template `{}` should print a JSON array of strings: {e}",
+            script.display()
+        )
+    })
+}
+
+/// Runs a post-processing tool on a module, given the path of the module as its only argument.
+///
+/// If the tool prints anything to stdout, that is taken to be the new module, otherwise the module
+/// is assumed to have been rewritten in place.
+fn run_post_process(tool: &Path, module: &Path) -> Result<(), String> {
+    let out = Command::new(tool).arg(module).output().map_err(|e| {
+        format!(
+            "failed to run post-processing tool `{}`: {e}",
+            tool.display()
+        )
+    })?;
+    if !out.status.success() {
+        return Err(format!(
+            "post-processing tool `{}` failed with {}: \n{}",
+            tool.display(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).replace('\n', "\n\t")
+        ));
+    }
+
+    if !out.stdout.is_empty() {
+        std::fs::write(module, &out.stdout).map_err(|e| {
+            format!(
+                "failed to write post-processed module to `{}`: {e}",
+                module.display()
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The name of the custom section added to modules built with `metadata_section: true`, for host code to find
+/// the section by when reading it back, e.g. with `wasmparser`.
+pub const METADATA_SECTION_NAME: &str = "include-wasm-rs";
+
+/// Encodes an integer as unsigned LEB128, as used for lengths in the WebAssembly binary format.
+fn write_leb128_u32(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Appends a custom section to the end of a module. Custom sections may appear anywhere,
+/// so the module remains valid.
+fn append_custom_section(module: &mut Vec<u8>, name: &str, payload: &[u8]) {
+    let mut name_bytes = Vec::new();
+    write_leb128_u32(&mut name_bytes, name.len() as u32);
+    name_bytes.extend_from_slice(name.as_bytes());
+
+    module.push(0); // Custom section id
+    write_leb128_u32(module, (name_bytes.len() + payload.len()) as u32);
+    module.extend_from_slice(&name_bytes);
+    module.extend_from_slice(payload);
+}
+
+/// Decodes an unsigned LEB128 integer from the start of some bytes, returning it and the number of bytes read.
+fn read_leb128_u32(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (i, byte) in bytes.iter().enumerate().take(5) {
+        value |= u32::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Removes every custom section from a module that the given predicate matches, by name.
+fn remove_custom_sections(
+    module: &[u8],
+    mut should_remove: impl FnMut(&[u8]) -> bool,
+) -> Result<Vec<u8>, String> {
+    const HEADER_LEN: usize = 8;
+    let malformed = || "failed to read sections of malformed module".to_owned();
+
+    if module.len() < HEADER_LEN || &module[..4] != b"\0asm" {
+        return Err(malformed());
+    }
+    let mut res = module[..HEADER_LEN].to_vec();
+
+    let mut rest = &module[HEADER_LEN..];
+    while let Some(&id) = rest.first() {
+        let (size, size_len) = read_leb128_u32(&rest[1..]).ok_or_else(malformed)?;
+        let section_len = 1 + size_len + size as usize;
+        let section = rest.get(..section_len).ok_or_else(malformed)?;
+
+        let remove = id == 0 && {
+            let contents = &section[1 + size_len..];
+            let (name_len, name_len_len) = read_leb128_u32(contents).ok_or_else(malformed)?;
+            let name = contents
+                .get(name_len_len..name_len_len + name_len as usize)
+                .ok_or_else(malformed)?;
+            should_remove(name)
+        };
+        if !remove {
+            res.extend_from_slice(section);
+        }
+
+        rest = &rest[section_len..];
+    }
+
+    Ok(res)
+}
+
+/// Removes the custom sections of a module that vary between machines or toolchain versions, despite
+/// the module being built from the same source.
+fn make_reproducible(module: &Path) -> Result<(), String> {
+    let bytes = std::fs::read(module)
+        .map_err(|e| format!("failed to read built module `{}`: {e}", module.display()))?;
+    // `producers` holds toolchain versions, while `name` and the DWARF sections hold symbols and
+    // paths which depend on where the module was built
+    let bytes = remove_custom_sections(&bytes, |name| {
+        name == b"producers" || name == b"name" || name.starts_with(b".debug")
+    })?;
+    std::fs::write(module, bytes).map_err(|e| {
+        format!(
+            "failed to write reproducible module `{}`: {e}",
+            module.display()
+        )
+    })
+}
+
+/// Overwrites the start of a data segment of a module with the given bytes, leaving the rest of the segment as it
+/// was. The layout of the module is unchanged, so it remains valid.
+fn inject_segment_data(module: &Path, segment: u32, data: &[u8]) -> Result<(), String> {
+    let mut bytes = std::fs::read(module)
+        .map_err(|e| format!("failed to read built module `{}`: {e}", module.display()))?;
+
+    let mut segment_count = 0;
+    let mut segment_range = None;
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        let payload =
+            payload.map_err(|e| format!("failed to parse module `{}`: {e}", module.display()))?;
+        if let wasmparser::Payload::DataSection(section) = payload {
+            segment_count = section.count();
+            if let Some(found) = section.into_iter().nth(segment as usize) {
+                let found = found
+                    .map_err(|e| format!("failed to parse module `{}`: {e}", module.display()))?;
+                // The segment's bytes are the end of its entry, which is within the bytes read
+                let end = found.range.end as usize;
+                segment_range = Some(end - found.data.len()..end);
+            }
+        }
+    }
+
+    let Some(segment_range) = segment_range else {
+        return Err(format!(
+            "module `{}` has no data segment {segment} to inject into, as it has {segment_count}",
+            module.display()
+        ));
+    };
+    if data.len() > segment_range.len() {
+        return Err(format!(
+            "the {} bytes to inject don't fit in data segment {segment} of module `{}`, which holds {} bytes",
+            data.len(),
+            module.display(),
+            segment_range.len()
+        ));
+    }
+    bytes[segment_range.start..segment_range.start + data.len()].copy_from_slice(data);
+
+    std::fs::write(module, bytes).map_err(|e| {
+        format!(
+            "failed to write injected data to module `{}`: {e}",
+            module.display()
+        )
+    })
+}
+
+/// Runs a command and gets the first line it prints, if it succeeds.
+fn command_output_line(command: &mut Command) -> Option<String> {
+    let out = command.output().ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(out.stdout).ok()?;
+    stdout.lines().next().map(str::to_owned)
+}
+
+/// Adds a custom section to a module, recording how and when it was built as a small JSON object.
+fn add_metadata_section(
+    module: &Path,
+    module_dir: &Path,
+    target_features: &str,
+    profile: &str,
+    toolchain: Option<&str>,
+) -> Result<(), String> {
+    let mut rustc = Command::new("rustc");
+    if let Some(toolchain) = toolchain {
+        rustc.arg(format!("+{toolchain}"));
+    }
+    let toolchain = command_output_line(rustc.arg("--version").current_dir(module_dir));
+    let commit = command_output_line(
+        Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(module_dir),
+    );
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    let optional = |value: Option<String>| value.map_or("null".to_owned(), |v| json_string(&v));
+    let metadata = format!(
+        "{{\"features\":{},\"profile\":{},\"toolchain\":{},\"commit\":{},\"timestamp\":{timestamp}}}",
+        json_string(target_features),
+        json_string(profile),
+        optional(toolchain),
+        optional(commit),
+    );
+
+    let mut bytes = std::fs::read(module)
+        .map_err(|e| format!("failed to read built module `{}`: {e}", module.display()))?;
+    append_custom_section(&mut bytes, METADATA_SECTION_NAME, metadata.as_bytes());
+    std::fs::write(module, bytes).map_err(|e| {
+        format!(
+            "failed to write metadata to module `{}`: {e}",
+            module.display()
+        )
+    })
+}
+
+/// Deletes a directory of build outputs, refusing to delete anything that isn't within the given target directory.
+fn remove_target_subdir(dir: &Path, target_dir: &Path) -> Result<(), String> {
+    let canonical = |path: &Path| {
+        path.canonicalize()
+            .map_err(|e| format!("failed to resolve `{}`: {e}", path.display()))
+    };
+    let dir = canonical(dir)?;
+    let target_dir = canonical(target_dir)?;
+
+    if dir == target_dir || !dir.starts_with(&target_dir) {
+        return Err(format!(
+            "refusing to delete `{}` as it is not within the target directory `{}`",
+            dir.display(),
+            target_dir.display()
+        ));
+    }
+
+    std::fs::remove_dir_all(&dir)
+        .map_err(|e| format!("failed to delete stale outputs in `{}`: {e}", dir.display()))
+}
+
+/// Deletes everything in a target directory other than its lock file, which must be held.
+fn clean_target_dir(target_dir: &Path) -> Result<(), String> {
+    let entries = std::fs::read_dir(target_dir).map_err(|e| {
+        format!(
+            "failed to read target directory `{}`: {e}",
+            target_dir.display()
+        )
+    })?;
+    for entry in entries {
+        let entry = entry.map_err(|e| {
+            format!(
+                "failed to read target directory `{}`: {e}",
+                target_dir.display()
+            )
+        })?;
+        if entry.file_name() == LOCK_FILE_NAME {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            remove_target_subdir(&path, target_dir)?;
+        } else {
+            std::fs::remove_file(&path)
+                .map_err(|e| format!("failed to delete `{}`: {e}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the module has a library or binary target at all, since a package without any sources, e.g.
+/// because the macro was pointed at the wrong directory, would otherwise be reported as lacking a `cdylib`.
+fn check_has_target(manifest: &toml::Table, module_dir: &Path) -> Result<(), String> {
+    // Workspace manifests have no targets of their own
+    if manifest.get("package").is_none() {
+        return Ok(());
+    }
+    // Targets given explicitly are checked by cargo, which reports missing sources well
+    let has_lib = manifest
+        .get("lib")
+        .is_some_and(|lib| lib.get("path").is_some())
+        || module_dir.join("src/lib.rs").is_file();
+    let has_bin = manifest
+        .get("bin")
+        .and_then(toml::Value::as_array)
+        .is_some_and(|bins| !bins.is_empty())
+        || module_dir.join("src/main.rs").is_file()
+        || module_dir.join("src/bin").is_dir();
+
+    if !has_lib && !has_bin {
+        return Err(format!(
+            "module `{}` has no library or binary target, as it has neither a `src/lib.rs` nor a `src/main.rs` - \
+            check that the module's path points at the directory of the crate to build",
+            module_dir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the module declares a target that builds to a `.wasm` file, since otherwise cargo
+/// succeeds without producing any output for us to find.
+fn check_produces_wasm(manifest: &toml::Table, module_dir: &Path) -> Result<(), String> {
+    let has_cdylib = manifest
+        .get("lib")
+        .and_then(|lib| lib.get("crate-type").or_else(|| lib.get("crate_type")))
+        .and_then(toml::Value::as_array)
+        .is_some_and(|crate_types| {
+            crate_types
+                .iter()
+                .any(|crate_type| crate_type.as_str() == Some("cdylib"))
+        });
+    let has_bin = manifest
+        .get("bin")
+        .and_then(toml::Value::as_array)
+        .is_some_and(|bins| !bins.is_empty())
+        || module_dir.join("src/main.rs").is_file()
+        || module_dir.join("src/bin").is_dir();
+
+    if !has_cdylib && !has_bin {
+        return Err(format!(
+            "module `{}` has no `cdylib` or `bin` target, so building it produces no `.wasm` file - \
+            try adding `crate-type = [\"cdylib\"]` to the `[lib]` section of its `Cargo.toml`",
+            module_dir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that the module doesn't use an edition before 2021 without choosing a resolver, since the
+/// version 1 resolver that those editions default to unifies the features of the standard library crates
+/// built by `-Z build-std` with those of the module's dependencies, which fails in confusing ways.
+fn check_edition(manifest: &toml::Table, module_dir: &Path) -> Result<(), String> {
+    let Some(package) = manifest.get("package") else {
+        return Ok(());
+    };
+    // Editions inherited from a workspace can't be checked without reading the workspace
+    let edition = match package.get("edition") {
+        None => "2015",
+        Some(toml::Value::String(edition)) => edition.as_str(),
+        Some(_) => return Ok(()),
+    };
+    if !matches!(edition, "2015" | "2018") {
+        return Ok(());
+    }
+    let has_resolver = package.get("resolver").is_some()
+        || manifest
+            .get("workspace")
+            .and_then(|workspace| workspace.get("resolver"))
+            .is_some();
+    if has_resolver {
+        return Ok(());
+    }
+
+    let given = if package.get("edition").is_some() {
+        "uses"
+    } else {
+        "has no `edition`, so defaults to"
+    };
+    Err(format!(
+        "module `{}` {given} the {edition} edition without choosing a resolver, so its dependencies' features \
+        may be unified with those of the standard library built by `-Z build-std`, which fails in confusing ways - \
+        try adding `edition = \"2021\"` or `resolver = \"2\"` to the `[package]` section of its `Cargo.toml`, \
+        or set `skip_manifest_checks: true`",
+        module_dir.display()
+    ))
+}
+
+/// The size of a WebAssembly memory page, in bytes.
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+/// The maximum size of the shared memory given to modules using atomics, if not given, in bytes. 1GiB.
+const DEFAULT_SHARED_MEMORY_MAX: u64 = 16384 * WASM_PAGE_SIZE;
+
+/// A simple hash which, unlike `DefaultHasher`, is stable between compiler versions.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The name of the file in a module's target directory recording the inputs of its last build with the given
+/// command, which is distinct for each module and configuration sharing the target directory.
+fn fingerprint_file_name(command_debug: &str) -> String {
+    format!(
+        ".include-wasm-rs.{:016x}.fingerprint",
+        fnv1a_hash(command_debug.as_bytes())
+    )
+}
+
+/// The output of `rustc -vV` for each toolchain and directory that have been asked for, or `None` where `rustc`
+/// couldn't be run, so that it is run at most once for each.
+static RUSTC_VERSIONS: Mutex<BTreeMap<String, Option<String>>> = Mutex::new(BTreeMap::new());
+
+/// Asks `rustc` for its version, including its commit and LLVM version, as run by cargo in the given directory,
+/// where a `rust-toolchain.toml` may choose the toolchain if none is given.
+fn rustc_version(toolchain: Option<&str>, dir: &Path) -> Option<String> {
+    let key = format!("{}\n{}", toolchain.unwrap_or("default"), dir.display());
+    let mut versions = RUSTC_VERSIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    versions
+        .entry(key)
+        .or_insert_with(|| {
+            let mut command = Command::new("rustc");
+            if let Some(toolchain) = toolchain {
+                command.arg(format!("+{toolchain}"));
+            }
+            let output = command
+                .arg("-vV")
+                .current_dir(dir)
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            Some(String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+        .clone()
+}
+
+/// Hashes everything that a build depends on: the command it runs, the version of the toolchain it runs,
+/// the processing applied to its output, and the contents of each of the module's files.
+fn build_fingerprint(
+    command_debug: &str,
+    toolchain: Option<&str>,
+    options: &BuildOptions,
+) -> String {
+    // Updating a toolchain, e.g. `nightly`, changes the compiler without changing the command
+    let rustc_version = rustc_version(toolchain, &options.module_dir);
+    let mut inputs = format!(
+        "{command_debug}\n{rustc_version:?}\n{}\n{}\n{:?}\n{:?}\n{}\n{}\n{:?}",
+        options.reproducible,
+        options.metadata_section,
+        options.post_process,
+        options.output_glob,
+        options.allow_multiple,
+        options.component,
+        options.adapter
+    );
+
+    if let Some(InjectData { segment, source }) = &options.inject_data {
+        let contents = source.read().ok().map(|bytes| fnv1a_hash(&bytes));
+        inputs += &format!("\ninject_data:{segment}:{source:?}:{contents:?}");
+    }
+    for (file, contents) in input_file_hashes(options) {
+        inputs += &format!("\n{file}:{contents:?}");
+    }
+
+    format!("{:016x}", fnv1a_hash(inputs.as_bytes()))
+}
+
+/// Hashes the contents of each file that a build depends on, giving `None` for files that can't be read.
+fn input_file_hashes(options: &BuildOptions) -> Vec<(String, Option<u64>)> {
+    let mut files = module_files(options);
+    for tool in [
+        &options.post_process,
+        &options.linker,
+        &options.adapter,
+        &options.command_template,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        files.push(tool.to_string_lossy().to_string());
+    }
+    if let Some(target) = options
+        .target
+        .as_ref()
+        .filter(|target| is_custom_target(target))
+    {
+        files.push(target.clone());
+    }
+
+    files
+        .into_iter()
+        .map(|file| {
+            let contents = std::fs::read(&file)
+                .ok()
+                .map(|bytes| hash_file_contents(bytes, options.normalize_line_endings));
+            (file, contents)
+        })
+        .collect()
+}
+
+/// Gives a record of the hashes of the files that a build depends on, with a line for each file.
+fn file_hashes_record(hashes: &[(String, Option<u64>)]) -> String {
+    hashes
+        .iter()
+        .map(|(file, contents)| match contents {
+            Some(contents) => format!("{contents:016x} {file}\n"),
+            None => format!("missing {file}\n"),
+        })
+        .collect()
+}
+
+/// Logs which of the files that a build depends on have changed since the record of the previous build was made,
+/// to explain why the module is being rebuilt.
+fn log_changed_files(record_path: &Path, hashes: &[(String, Option<u64>)]) {
+    let Ok(previous) = std::fs::read_to_string(record_path) else {
+        debug_log(
+            "no record of the files used by a previous build, so can't tell which have changed",
+        );
+        return;
+    };
+    let previous = previous
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(contents, file)| (file, contents))
+        .collect::<Vec<_>>();
+    let current = file_hashes_record(hashes);
+    let current = current
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(contents, file)| (file, contents))
+        .collect::<Vec<_>>();
+
+    let mut changed = false;
+    for (file, contents) in &current {
+        match previous
+            .iter()
+            .find(|(previous_file, _)| previous_file == file)
+        {
+            Some((_, previous_contents)) if previous_contents == contents => continue,
+            Some(_) => debug_log(format_args!(
+                "file `{file}` has changed since the last build"
+            )),
+            None => debug_log(format_args!("file `{file}` is new since the last build")),
+        }
+        changed = true;
+    }
+    for (file, _) in &previous {
+        if !current.iter().any(|(current_file, _)| current_file == file) {
+            debug_log(format_args!(
+                "file `{file}` is no longer used since the last build"
+            ));
+            changed = true;
+        }
+    }
+    if !changed {
+        debug_log(
+            "no files have changed since the last build, so it is rebuilt due to other options",
+        );
+    }
+}
+
+/// Hashes the contents of a file, optionally replacing CRLF line endings with LF in text files, so that
+/// checkouts on different platforms give the same hash. Files containing a NUL byte are taken to be binary,
+/// and are hashed as they are.
+fn hash_file_contents(bytes: Vec<u8>, normalize_line_endings: bool) -> u64 {
+    if !normalize_line_endings || bytes.contains(&0) {
+        return fnv1a_hash(&bytes);
+    }
+
+    let mut normalized = Vec::with_capacity(bytes.len());
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte == b'\r' && bytes.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        normalized.push(*byte);
+    }
+    fnv1a_hash(&normalized)
+}
+
+/// The longest that a module's target directory may be before the `env` part of its name is hashed, leaving room
+/// for the paths that cargo creates within it under Windows' default limit of 260 characters.
+///
+/// This applies on every platform, not only on Windows, since the `env` part of the name is a single path
+/// component, which most other filesystems limit to 255 bytes.
+const MAX_TARGET_DIR_LEN: usize = 120;
+
+/// The name of the file in a module's target directory used to lock it.
+const LOCK_FILE_NAME: &str = ".include-wasm-rs.lock";
+
+/// Takes an exclusive lock on a target directory, shared between processes, which is released when the
+/// returned file is dropped.
+fn lock_target_dir(target_dir: &Path) -> Result<std::fs::File, String> {
+    std::fs::create_dir_all(target_dir).map_err(|e| {
+        format!(
+            "failed to create target directory `{}`: {e}",
+            target_dir.display()
+        )
+    })?;
+
+    let lock_path = target_dir.join(LOCK_FILE_NAME);
+    let lock_file = std::fs::File::create(&lock_path)
+        .map_err(|e| format!("failed to create lock file `{}`: {e}", lock_path.display()))?;
+    lock_file
+        .lock()
+        .map_err(|e| format!("failed to lock `{}`: {e}", lock_path.display()))?;
+
+    Ok(lock_file)
+}
+
+/// Only allow one `cargo update` at a time, in case we are building one module many times, since
+/// every configuration of a module shares its `Cargo.lock`. Builds themselves are serialized by
+/// the lock on their target directory.
+static GLOBAL_LOCK: Mutex<()> = Mutex::new(());
+
+/// The cargo invocation that builds a module, along with the details of it needed to find and describe the output.
+struct BuildPlan {
+    command: Command,
+    /// Relative to the module directory.
+    target_dir: String,
+    target_features: String,
+    /// The toolchain passed to cargo, if any.
+    toolchain: Option<String>,
+    /// Warnings about the build for the caller to show, since this library doesn't print them itself.
+    warnings: Vec<String>,
+}
+
+/// Constructs the cargo command used to build a module, without running anything.
+fn plan_build(options: &BuildOptions) -> Result<BuildPlan, String> {
+    let BuildOptions {
+        module_dir,
+        features,
+        env_vars,
+        release,
+        opt_level,
+        lto,
+        codegen_units,
+        raw_target_features,
+        panic,
+        size_optimized,
+        build_std,
+        build_std_features,
+        z_flags,
+        config,
+        linker,
+        link_args,
+        deny_warnings,
+        web_sys_unstable,
+        shared_memory_max,
+        clean_stale: _,
+        clean: _,
+        allow_multiple: _,
+        bin,
+        output_glob: _,
+        post_process: _,
+        inject_data: _,
+        metadata_section: _,
+        merge_config_rustflags,
+        reproducible: _,
+        incremental,
+        auto_features,
+        skip_manifest_checks: _,
+        skip_feature_checks,
+        strict_features,
+        component: _,
+        adapter: _,
+        command_template,
+        jobs,
+        quiet,
+        cache: _,
+        normalize_line_endings: _,
+        track_ignore: _,
+        manifest_path,
+        target_dir: explicit_target_dir,
+        share_std,
+        toolchain,
+        target,
+    } = options;
+
+    // The preset only fills in the settings that weren't given
+    let opt_level = opt_level.as_deref().or(size_optimized.then_some("z"));
+    let lto = lto.as_deref().or(size_optimized.then_some("fat"));
+    let codegen_units = codegen_units.or(size_optimized.then_some(1));
+    let panic = panic.as_deref().or(size_optimized.then_some("abort"));
+
+    let target = target.as_deref().unwrap_or(DEFAULT_TARGET);
+    // Keep the panic strategy consistent with the panic crate that std is built with. WASI targets are left with
+    // std's default panic crate unless a panic strategy is chosen.
+    let build_std = match build_std {
+        Some(build_std) => build_std.clone(),
+        None => match panic {
+            Some("unwind") => vec!["panic_unwind".to_owned(), "std".to_owned()],
+            None if target.contains("-wasi") => vec!["std".to_owned()],
+            _ => vec!["panic_abort".to_owned(), "std".to_owned()],
+        },
+    };
+    if build_std.is_empty() && !build_std_features.is_empty() {
+        return Err("`build_std_features` requires a non-empty `build_std`".to_owned());
+    }
+    // A custom target spec is given to cargo by path, so must be absolute as cargo runs in the module's directory
+    let custom_target;
+    let target = if is_custom_target(target) {
+        if build_std.is_empty() {
+            return Err(format!(
+                "custom target `{target}` requires a non-empty `build_std`, as it has no prebuilt standard library"
+            ));
+        }
+        custom_target = Path::new(target)
+            .canonicalize()
+            .map_err(|e| format!("failed to read custom target spec `{target}`: {e}"))?;
+        custom_target
+            .to_str()
+            .expect("custom target spec path should be unicode compliant")
+    } else {
+        target
+    };
+    if let Some(panic) = panic {
+        let panic_crate = format!("panic_{panic}");
+        let other_panic_crate = if panic == "abort" {
+            "panic_unwind"
+        } else {
+            "panic_abort"
+        };
+        if build_std.iter().any(|krate| krate == other_panic_crate)
+            && !build_std.contains(&panic_crate)
+        {
+            return Err(format!(
+                "panic strategy `{panic}` requires `{panic_crate}` in `build_std`, but `build_std` contains `{other_panic_crate}` instead"
+            ));
+        }
+    }
+
+    // Construct build command
+    let mut command = Command::new("cargo");
+
+    // Treat `RUSTFLAGS` as special in env vars
+    const RUSTFLAGS: &str = "RUSTFLAGS";
+    // Cargo prefers `CARGO_ENCODED_RUSTFLAGS` to `RUSTFLAGS`, so one inherited from the host would replace our flags
+    const CARGO_ENCODED_RUSTFLAGS: &str = "CARGO_ENCODED_RUSTFLAGS";
+    command.env_remove(CARGO_ENCODED_RUSTFLAGS);
+    // Features requested by the module itself are added to those given to the macro
+    let mut features = features.clone();
+    if *auto_features {
+        let crate_dir = options.crate_dir();
+        if let Some(manifest) = read_manifest(&crate_dir) {
+            features.merge(TargetFeatures::from_manifest(&manifest, &crate_dir)?);
+        }
+    }
+    if !*skip_feature_checks {
+        features.check(target)?;
+    }
+    let structured_features = features.to_string();
+    let mut target_features = vec![
+        structured_features.trim_end_matches(','),
+        raw_target_features.as_str(),
+    ];
+    let mut user_rustflags = Vec::new();
+    // Setting `RUSTFLAGS` makes cargo ignore rustflags from config files, so merge them in ourselves
+    let config_rustflags = if *merge_config_rustflags {
+        read_config_rustflags(module_dir, target_name(target))?
+    } else {
+        Vec::new()
+    };
+    let (flags, features) = split_target_features(config_rustflags.iter().map(String::as_str));
+    user_rustflags.extend(flags);
+    target_features.extend(features);
+    if !*incremental {
+        // Set first, so that a value given in `env` takes precedence
+        command.env("CARGO_INCREMENTAL", "0");
+    }
+    for (key, val) in env_vars.iter() {
+        if key == RUSTFLAGS {
+            // Merge any user target features into our single `-C target-feature` flag. Theirs come last, so win.
+            let (flags, features) = split_target_features(val.split_whitespace());
+            user_rustflags.extend(flags);
+            target_features.extend(features);
+        } else if key == CARGO_ENCODED_RUSTFLAGS {
+            // Merged in the same way, but must survive being re-encoded as `RUSTFLAGS`
+            let encoded_flags = val.split('\x1f').filter(|flag| !flag.is_empty());
+            if let Some(flag) = encoded_flags
+                .clone()
+                .find(|flag| flag.contains(char::is_whitespace))
+            {
+                return Err(format!(
+                    "`{CARGO_ENCODED_RUSTFLAGS}` flag `{flag}` contains whitespace, so cannot be merged into `{RUSTFLAGS}`"
+                ));
+            }
+            let (flags, features) = split_target_features(encoded_flags);
+            user_rustflags.extend(flags);
+            target_features.extend(features);
+        } else {
+            command.env(key, val);
+        }
+    }
+    target_features.retain(|features| !features.is_empty());
+
+    // Each flag is kept separate until the end, so that any of them can be left out independently
+    let mut rustflags = Vec::new();
+    if *web_sys_unstable {
+        rustflags.push("--cfg=web_sys_unstable_apis".to_owned());
+    }
+    rustflags.push(format!("-C target-feature={}", target_features.join(",")));
+    if let Some(opt_level) = opt_level {
+        rustflags.push(format!("-C opt-level={opt_level}"));
+    }
+    if let Some(lto) = lto {
+        rustflags.push(format!("-C lto={lto}"));
+    }
+    if let Some(codegen_units) = codegen_units {
+        rustflags.push(format!("-C codegen-units={codegen_units}"));
+    }
+    if let Some(panic) = panic {
+        rustflags.push(format!("-C panic={panic}"));
+    }
+    if *size_optimized {
+        rustflags.push("-C strip=debuginfo".to_owned());
+    }
+    for flag in z_flags {
+        rustflags.push(format!("-Z {flag}"));
+    }
+    if let Some(linker) = linker {
+        if linker.components().count() > 1 && !linker.is_file() {
+            return Err(format!("linker `{}` does not exist", linker.display()));
+        }
+        rustflags.push(format!(
+            "-C linker={}",
+            linker.to_str().expect("linker should be unicode compliant")
+        ));
+    }
+    for arg in link_args {
+        rustflags.push(format!("-C link-arg={arg}"));
+    }
+    // Engines reject modules using atomics without a shared memory, so give them one unless told otherwise
+    let atomics = target_features
+        .iter()
+        .flat_map(|features| features.split(','))
+        .rfind(|feature| feature.trim_start_matches(['+', '-']) == "atomics")
+        == Some("+atomics");
+    if atomics {
+        let max_memory = format!(
+            "--max-memory={}",
+            shared_memory_max.unwrap_or(DEFAULT_SHARED_MEMORY_MAX)
+        );
+        for arg in ["--shared-memory", "--import-memory", &max_memory] {
+            let flag = arg.split('=').next().unwrap_or(arg);
+            if !link_args.iter().any(|user_arg| user_arg.starts_with(flag)) {
+                rustflags.push(format!("-C link-arg={arg}"));
+            }
+        }
+    }
+    // Unless the user has already denied warnings themselves
+    let user_denies_warnings = user_rustflags.contains(&"-Dwarnings")
+        || user_rustflags
+            .windows(2)
+            .any(|flags| flags == ["-D", "warnings"]);
+    if *deny_warnings && !user_denies_warnings {
+        rustflags.push("-D warnings".to_owned());
+    }
+    rustflags.extend(user_rustflags.iter().map(|flag| flag.to_string()));
+    let rustflags_value = rustflags.join(" ");
+    command.env(RUSTFLAGS, &rustflags_value);
+
+    // `RUSTC_BOOTSTRAP` unlocks unstable flags on any toolchain, so we don't need to force nightly
+    let bootstrap = env_vars.iter().any(|(key, _)| key == RUSTC_BOOTSTRAP)
+        || std::env::var_os(RUSTC_BOOTSTRAP).is_some();
+    let toolchain = match toolchain {
+        Some(toolchain) => Some(toolchain.as_str()),
+        None if bootstrap => None,
+        None => Some("nightly"),
+    };
+    if *strict_features {
+        // Unknown features are only warned about by rustc, so would otherwise be silently ignored
+        if let Some(known) = known_target_features(toolchain, target) {
+            let unknown = target_features
+                .iter()
+                .flat_map(|features| features.split(','))
+                .map(|feature| feature.trim().trim_start_matches(['+', '-']))
+                .filter(|feature| {
+                    !feature.is_empty() && !known.iter().any(|known| known == feature)
+                })
+                .collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                return Err(format!(
+                    "unknown target features {} for `{target}` with the `{}` toolchain - \
+                    check their names, or that the toolchain is new enough to support them",
+                    unknown
+                        .iter()
+                        .map(|feature| format!("`{feature}`"))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    toolchain.unwrap_or("default")
+                ));
+            }
+        }
+    }
+
+    // Build output path. Each configuration gets its own directory, so that building a module in one
+    // configuration doesn't invalidate cargo's cache of the module built in another.
+    let configuration = format!(
+        "{}\n{target}\n{release}\n{}\n{}\n{rustflags_value}",
+        toolchain.unwrap_or("default"),
+        build_std.join(","),
+        build_std_features.join(",")
+    );
+    let mut warnings = Vec::new();
+    let target_dir = match explicit_target_dir {
+        // Relative to the current directory, rather than the module directory that cargo runs in
+        Some(target_dir) => std::env::current_dir()
+            .map_err(|e| format!("failed to get current directory: {e}"))?
+            .join(target_dir)
+            .to_str()
+            .expect("target directory should be unicode compliant")
+            .to_owned(),
+        None if *share_std => {
+            // Modules only share the standard library if they share a directory, so leave out everything
+            // specific to a module, leaving cargo to rebuild those crates whose env changes
+            let host_target_dir = match std::env::var_os(CARGO_TARGET_DIR) {
+                Some(host_target_dir) => PathBuf::from(host_target_dir),
+                None => std::env::var_os("CARGO_MANIFEST_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_default()
+                    .join("target"),
+            };
+            let host_target_dir = std::env::current_dir()
+                .map_err(|e| format!("failed to get current directory: {e}"))?
+                .join(host_target_dir);
+            format!(
+                "{}/wasm-std-{}-{:016x}",
+                host_target_dir
+                    .to_str()
+                    .expect("target directory should be unicode compliant"),
+                toolchain.unwrap_or("default"),
+                fnv1a_hash(configuration.as_bytes())
+            )
+        }
+        None => {
+            // Keep all build output under the host's target directory if it has moved it, in which case
+            // the directory is shared between modules
+            let mut target_dir = match std::env::var_os(CARGO_TARGET_DIR) {
+                Some(host_target_dir) => {
+                    let host_target_dir = std::env::current_dir()
+                        .map_err(|e| format!("failed to get current directory: {e}"))?
+                        .join(host_target_dir);
+                    let configuration = format!("{}\n{configuration}", module_dir.display());
+                    format!(
+                        "{}/wasm-guests/{:016x}",
+                        host_target_dir
+                            .to_str()
+                            .expect("target directory should be unicode compliant"),
+                        fnv1a_hash(configuration.as_bytes())
+                    )
+                }
+                None => format!("target/{:016x}", fnv1a_hash(configuration.as_bytes())),
+            };
+            // Values can be anything, e.g. JSON or the contents of files, so only their hash is used in the name
+            let mut env_suffix = String::new();
+            let mut env_values = String::new();
+            for (key, val) in env_vars.iter() {
+                env_suffix += &format!("_{key}");
+                env_values += &format!("{key}={val}\n");
+            }
+            if !env_vars.is_empty() {
+                env_suffix += &format!("_{:016x}", fnv1a_hash(env_values.as_bytes()));
+            }
+            let base_len = std::path::absolute(module_dir.join(&target_dir))
+                .map_or(target_dir.len(), |path| path.as_os_str().len());
+            if base_len + env_suffix.len() > MAX_TARGET_DIR_LEN {
+                // Otherwise cargo fails to create the directory, with an obscure IO error
+                warnings.push(format!(
+                    "the `env` of module `{}` would make its target directory longer than {MAX_TARGET_DIR_LEN} \
+                    characters, so it is hashed instead",
+                    module_dir.display()
+                ));
+                target_dir += &format!(
+                    "_env{:016x}",
+                    fnv1a_hash(format!("{env_suffix}\n{env_values}").as_bytes())
+                );
+            } else {
+                target_dir += &env_suffix;
+            }
+            target_dir
+        }
+    };
+
+    // Set args
+    let toolchain_arg = toolchain.map(|toolchain| format!("+{toolchain}"));
+    let build_std_arg = format!("build-std={}", build_std.join(","));
+    let build_std_features_arg = format!("build-std-features={}", build_std_features.join(","));
+    let jobs_arg = jobs.map(|jobs| jobs.to_string());
+    let mut cargo_args = Vec::new();
+    if let Some(toolchain_arg) = &toolchain_arg {
+        cargo_args.push(toolchain_arg.as_str());
+    }
+    cargo_args.extend([
+        "build",
+        "--target",
+        target,
+        // Artifact messages go to stdout, while diagnostics are still rendered to stderr
+        "--message-format=json-render-diagnostics",
+    ]);
+    if !build_std.is_empty() {
+        cargo_args.extend(["-Z", &build_std_arg]);
+    }
+    if !build_std_features.is_empty() {
+        cargo_args.extend(["-Z", &build_std_features_arg]);
+    }
+    cargo_args.extend(["--target-dir", &target_dir]);
+    if let Some(manifest_path) = manifest_path {
+        cargo_args.extend([
+            "--manifest-path",
+            manifest_path
+                .to_str()
+                .expect("manifest path should be unicode compliant"),
+        ]);
+    }
+    if *release {
+        cargo_args.push("--release");
+    }
+    for config in config {
+        cargo_args.extend(["--config", config]);
+    }
+    if let Some(bin) = bin {
+        cargo_args.extend(["--bin", bin]);
+    }
+    if let Some(jobs_arg) = &jobs_arg {
+        cargo_args.extend(["--jobs", jobs_arg]);
+    }
+    if *quiet {
+        // Errors are still printed with `--quiet`
+        cargo_args.push("--quiet");
+        command
+            .env("CARGO_TERM_PROGRESS_WHEN", "never")
+            .env("TERM", "dumb");
+    }
+
+    let cargo_args = match command_template {
+        Some(script) if script.is_file() => apply_command_template(script, &cargo_args)?,
+        Some(script) => {
+            debug_log(format_args!(
+                "command template `{}` doesn't exist, so the planned command is used",
+                script.display()
+            ));
+            cargo_args.into_iter().map(str::to_owned).collect()
+        }
+        None => cargo_args.into_iter().map(str::to_owned).collect(),
+    };
+
+    command.args(cargo_args).current_dir(module_dir.clone());
+
+    Ok(BuildPlan {
+        command,
+        target_dir,
+        target_features: target_features.join(","),
+        toolchain: toolchain.map(str::to_owned),
+        warnings,
+    })
+}
+
+/// Gives the cargo command that [`build`] would run for a module, without running anything.
+pub fn build_command(options: &BuildOptions) -> Result<Command, String> {
+    plan_build(options).map(|plan| plan.command)
+}
+
+/// Gives warnings about how [`build`] would build a module, e.g. that its target directory had to be shortened,
+/// for the caller to show. Options that can't be built give no warnings, as [`build`] reports them as errors.
+pub fn build_warnings(options: &BuildOptions) -> Vec<String> {
+    plan_build(options)
+        .map(|plan| plan.warnings)
+        .unwrap_or_default()
+}
+
+/// Builds a cargo project as a webassembly module, returning the path of the module produced.
+pub fn build(options: &BuildOptions) -> Result<PathBuf, String> {
+    // Options that change the command are handled by `plan_build`
+    let BuildOptions {
+        module_dir,
+        release,
+        clean_stale,
+        clean,
+        allow_multiple,
+        bin,
+        output_glob,
+        post_process,
+        inject_data,
+        metadata_section,
+        reproducible,
+        component,
+        adapter,
+        cache,
+        skip_manifest_checks,
+        manifest_path,
+        ..
+    } = options;
+
+    // Check target path points to a module
+    if !module_dir.exists() {
+        return Err(format!(
+            "module directory `{}` does not exist",
+            module_dir.display()
+        ));
+    }
+    if !module_dir.is_dir() {
+        return Err(format!(
+            "module path `{}` is not a directory",
+            module_dir.display()
+        ));
+    }
+    if let Some(manifest_path) = manifest_path {
+        let manifest_path = module_dir.join(manifest_path);
+        if manifest_path.file_name() != Some("Cargo.toml".as_ref()) || !manifest_path.is_file() {
+            return Err(format!(
+                "manifest path `{}` does not point to a `Cargo.toml` file",
+                manifest_path.display()
+            ));
+        }
+    }
+    let crate_dir = options.crate_dir();
+    let cargo_config = crate_dir.join("Cargo.toml");
+    if !cargo_config.is_file() {
+        return Err(format!(
+            "target directory `{}` does not contain a `Cargo.toml` file",
+            crate_dir.display()
+        ));
+    }
+    // A crate including itself would build itself again, waiting forever on its own target directory
+    if let Some(host_dir) = std::env::var_os("CARGO_MANIFEST_DIR") {
+        let host_dir = Path::new(&host_dir).canonicalize().ok();
+        if host_dir.is_some() && crate_dir.canonicalize().ok() == host_dir {
+            return Err(format!(
+                "module `{}` is the crate being compiled, which can't include itself",
+                crate_dir.display()
+            ));
+        }
+    }
+    match std::fs::read_to_string(cargo_config) {
+        Ok(cfg) => {
+            if cfg.contains("[workspace]\n") {
+                return Err("provided directory points to a workspace, not a module".to_owned());
+            }
+            // Malformed manifests are left for cargo to report
+            if let Ok(manifest) = cfg.parse::<toml::Table>() {
+                if !*skip_manifest_checks {
+                    check_has_target(&manifest, &crate_dir)?;
+                    check_produces_wasm(&manifest, &crate_dir)?;
+                    check_edition(&manifest, &crate_dir)?;
+                }
+            }
+        }
+        Err(e) => return Err(format!("failed to read target `Cargo.toml`: {e}")),
+    }
+
+    let BuildPlan {
+        mut command,
+        target_dir,
+        target_features,
+        toolchain,
+        // Callers show these through `build_warnings`
+        warnings: _,
+    } = plan_build(options)?;
+
+    // The global lock only covers this compiler process, so also lock the target directory against
+    // other processes, e.g. `cargo check` and `cargo build` of the host running at once
+    let _target_dir_lock = lock_target_dir(&module_dir.join(&target_dir))?;
+
+    // Only directories chosen by us are cleaned, since a given one may be shared with anything
+    if *clean {
+        if options.target_dir.is_some() {
+            return Err("`clean` cannot be used with `target_dir`".to_owned());
+        }
+        // A shared directory holds the builds of other modules too
+        if options.share_std {
+            return Err("`clean` cannot be used with `share_std`".to_owned());
+        }
+        clean_target_dir(&module_dir.join(&target_dir))?;
+    }
+
+    let command_debug = format!("{command:?}");
+    debug_log(format_args!(
+        "building module `{}` in target directory `{}`",
+        module_dir.display(),
+        module_dir.join(&target_dir).display()
+    ));
+    debug_log(format_args!("running `{command_debug}`"));
+
+    // Skip even spawning cargo if nothing has changed since the last build
+    let fingerprint_path = module_dir
+        .join(&target_dir)
+        .join(fingerprint_file_name(&command_debug));
+    let fingerprint = build_fingerprint(&command_debug, toolchain.as_deref(), options);
+    // Only kept while logging, as working out which files changed means hashing them all again
+    let files_record_path = fingerprint_path.with_extension("files");
+    let file_hashes = debug_logging().then(|| input_file_hashes(options));
+    if *cache {
+        let previous = std::fs::read_to_string(&fingerprint_path).unwrap_or_default();
+        if let Some((previous_fingerprint, previous_output)) = previous.split_once('\n') {
+            let previous_output = PathBuf::from(previous_output);
+            if previous_fingerprint == fingerprint && previous_output.is_file() {
+                debug_log(format_args!(
+                    "skipped build, as nothing has changed since `{}` was built",
+                    previous_output.display()
+                ));
+                return Ok(previous_output);
+            }
+        }
+    }
+    if let Some(file_hashes) = &file_hashes {
+        log_changed_files(&files_record_path, file_hashes);
+    }
+
+    // Acquire global lock
+    let mut lock = GLOBAL_LOCK.lock();
+    while lock.is_err() {
+        GLOBAL_LOCK.clear_poison();
+        lock = GLOBAL_LOCK.lock();
+    }
+
+    // Run `cargo update` before building
+    let out = Command::new("cargo")
+        .arg("update")
+        .current_dir(module_dir.clone())
+        .output();
+    match out {
+        Ok(out) => {
+            if !out.status.success() {
+                return Err(format!(
+                    "failed to update module `{}`: \n{}",
+                    module_dir.display(),
+                    String::from_utf8_lossy(&out.stderr).replace('\n', "\n\t")
+                ));
+            }
+        }
+        Err(e) => {
+            return Err(format!(
+                "failed to update module `{}`: {e}",
+                module_dir.display()
+            ))
+        }
+    }
+    drop(lock);
+
+    let root_output = module_dir.join(&target_dir).join(target_name(
+        options.target.as_deref().unwrap_or(DEFAULT_TARGET),
+    ));
+    let profile_output = if *release {
+        root_output.join("release/")
+    } else {
+        root_output.join("debug/")
+    };
+    let default_glob = match bin {
+        Some(bin) => format!("{}.wasm", glob::Pattern::escape(bin)),
+        None => "*.wasm".to_owned(),
+    };
+    let glob = glob_pattern(
+        &profile_output,
+        output_glob.as_deref().unwrap_or(&default_glob),
+    );
+
+    // Only clean and rebuild once before giving up
+    let mut may_clean_stale = *clean_stale;
+    let output = loop {
+        let out = command.output();
+        let out = match out {
+            Ok(out) => {
+                if !out.status.success() {
+                    let stderr = String::from_utf8_lossy(&out.stderr);
+                    return Err(map_build_error(
+                        &out.stdout,
+                        &stderr,
+                        options,
+                        &command_debug,
+                    ));
+                }
+                out
+            }
+            Err(e) => {
+                return Err(map_build_error(
+                    b"",
+                    &e.to_string(),
+                    options,
+                    &command_debug,
+                ))
+            }
+        };
+
+        // Prefer the exact artifact that cargo reports, unless told which file to use
+        if output_glob.is_none() {
+            if let Some(output) = find_reported_artifact(&out.stdout, &crate_dir, bin.as_deref())? {
+                break output;
+            }
+        }
+
+        // Else find output with glob, whose results are sorted by filename so that the first is deterministic.
+        // The default pattern shouldn't find copies of the module, or of its examples, in intermediate directories.
+        let mut glob_paths = glob::glob(&glob)
+            .expect("glob should be valid")
+            .filter(|path| {
+                output_glob.is_some()
+                    || !path
+                        .as_ref()
+                        .is_ok_and(|path| is_intermediate(path, &profile_output))
+            });
+
+        let output = match glob_paths.next() {
+            Some(Ok(output)) => output,
+            Some(Err(err)) => {
+                return Err(format!(
+                "failed to find output file matching `{glob:?}`: {err} - this is probably a bug",
+            ))
+            }
+            None => {
+                return Err(format!(
+                    "failed to find output file matching `{glob}` - this is probably a bug",
+                ))
+            }
+        };
+
+        // Check only one output to avoid hidden bugs, unless told to use the first
+        if *allow_multiple {
+            break output;
+        }
+        if let Some(Ok(_)) = glob_paths.next() {
+            if may_clean_stale {
+                may_clean_stale = false;
+                remove_target_subdir(&profile_output, &module_dir.join(&target_dir))?;
+                continue;
+            }
+
+            return Err(format!("multiple output files matching `{}` were found - this may be because you recently changed the name of your module; try deleting the folder `{}` and rebuilding, or set `clean_stale: true` or `allow_multiple: true`", glob, root_output.display()));
+        }
+
+        break output;
+    };
+
+    debug_log(format_args!("found output `{}`", output.display()));
+
+    let built = output.clone();
+    let mut output = output;
+    if *reproducible || *metadata_section || inject_data.is_some() || post_process.is_some() {
+        output = copy_for_processing(&output, &profile_output)?;
+    }
+    if *reproducible {
+        make_reproducible(&output)?;
+    }
+    if *metadata_section {
+        let profile = if *release { "release" } else { "debug" };
+        add_metadata_section(
+            &output,
+            module_dir,
+            &target_features,
+            profile,
+            toolchain.as_deref(),
+        )?;
+    }
+    if let Some(InjectData { segment, source }) = inject_data {
+        inject_segment_data(&output, *segment, &source.read()?)?;
+    }
+    if let Some(tool) = post_process {
+        run_post_process(tool, &output)?;
+    }
+    if *component {
+        output = make_component(&output, adapter.as_deref(), &profile_output)?;
+    }
+    if output != built {
+        let len = |path: &Path| std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        debug_log(format_args!(
+            "processed output is {} bytes, from {} bytes",
+            len(&output),
+            len(&built)
+        ));
+    }
+
+    if *cache {
+        // Failing to write the fingerprint only means that the next build isn't skipped
+        let _ = std::fs::write(
+            &fingerprint_path,
+            format!("{fingerprint}\n{}", output.display()),
+        );
+    }
+    if let Some(file_hashes) = &file_hashes {
+        let _ = std::fs::write(&files_record_path, file_hashes_record(file_hashes));
+    }
+
+    Ok(output)
+}
+
+/// Builds many modules at once, using up to `jobs` threads (or as many as are available, if not given),
+/// giving the result of building each module in order.
+///
+/// Modules sharing a target directory are still built one at a time.
+pub fn build_many(options: &[BuildOptions], jobs: Option<usize>) -> Vec<Result<PathBuf, String>> {
+    let jobs = jobs
+        .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(1)
+        .clamp(1, options.len().max(1));
+
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; options.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let Some(options) = options.get(i) else {
+                    break;
+                };
+                let result = build(options);
+                results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err("module was not built".to_owned())))
+        .collect()
+}
+
+/// The directories of a profile's output in which cargo keeps intermediate artifacts, rather than the final ones.
+const INTERMEDIATE_DIRS: [&str; 4] = ["deps", "incremental", "examples", "build"];
+
+/// Checks if a file in a profile's output directory is within one of its intermediate directories.
+fn is_intermediate(path: &Path, profile_output: &Path) -> bool {
+    path.strip_prefix(profile_output).is_ok_and(|path| {
+        path.components().any(|component| {
+            INTERMEDIATE_DIRS
+                .iter()
+                .any(|dir| component.as_os_str() == *dir)
+        })
+    })
+}
+
+/// Builds a pattern matching `pattern` within `dir`, using forward slashes as separators, since the `glob`
+/// crate treats backslashes as escapes on some platforms, and they are separators in Windows paths.
+/// Any special characters in `dir`, e.g. in the path of the host's target directory, are escaped.
+fn glob_pattern(dir: &Path, pattern: &str) -> String {
+    let dir = dir.to_str().expect("path should be unicode compliant");
+    let dir = if cfg!(windows) {
+        dir.replace('\\', "/")
+    } else {
+        dir.to_owned()
+    };
+    format!(
+        "{}/{pattern}",
+        glob::Pattern::escape(dir.trim_end_matches('/'))
+    )
+}
+
+/// Gets the files matching a pattern. A file reached more than once through symlinks, e.g. by a symlink loop,
+/// is only given once.
+fn glob_files(pattern: String) -> Vec<String> {
+    let glob_paths = glob::glob(&pattern).expect("glob should be valid");
+
+    let mut seen = Vec::new();
+    glob_paths
+        .into_iter()
+        .filter_map(|path| {
+            let path = path.ok()?;
+            if !path.is_file() {
+                return None;
+            }
+            let canonical = path.canonicalize().ok()?;
+            if seen.contains(&canonical) {
+                return None;
+            }
+            seen.push(canonical);
+            Some(path.to_string_lossy().to_string())
+        })
+        .collect()
+}
+
+/// Reads the `Cargo.toml` of the crate in the given directory, if there is one and it is valid.
+fn read_manifest(crate_dir: &Path) -> Option<toml::Table> {
+    let contents = std::fs::read_to_string(crate_dir.join("Cargo.toml")).ok()?;
+    contents.parse::<toml::Table>().ok()
+}
+
+/// Gets the `path` of every dependency that could contribute to a build of the crate with the given manifest.
+fn path_dependencies(manifest: &toml::Table) -> Vec<String> {
+    const DEPENDENCY_KEYS: [&str; 2] = ["dependencies", "build-dependencies"];
+
+    let mut tables = Vec::new();
+    for key in DEPENDENCY_KEYS {
+        tables.extend(manifest.get(key).and_then(toml::Value::as_table));
+    }
+    // Platform specific dependencies, e.g. `[target.'cfg(target_arch = "wasm32")'.dependencies]`
+    if let Some(targets) = manifest.get("target").and_then(toml::Value::as_table) {
+        for target in targets.values() {
+            for key in DEPENDENCY_KEYS {
+                tables.extend(target.get(key).and_then(toml::Value::as_table));
+            }
+        }
+    }
+    // Patches, e.g. `[patch.crates-io]`
+    if let Some(sources) = manifest.get("patch").and_then(toml::Value::as_table) {
+        tables.extend(sources.values().filter_map(toml::Value::as_table));
+    }
+
+    tables
+        .into_iter()
+        .flat_map(toml::Table::values)
+        .filter_map(|dependency| dependency.get("path")?.as_str().map(str::to_owned))
+        .collect()
+}
+
+/// Finds the given crate directory, along with the directories of all the crates it depends on by path, recursively.
+///
+/// Dependencies which don't exist are skipped, leaving cargo to report them when building.
+fn local_crate_dirs(module_dir: PathBuf) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut to_visit = vec![module_dir];
+
+    while let Some(crate_dir) = to_visit.pop() {
+        let Ok(crate_dir) = crate_dir.canonicalize() else {
+            continue;
+        };
+        // Also protects against cycles
+        if found.contains(&crate_dir) {
+            continue;
+        }
+
+        if let Some(manifest) = read_manifest(&crate_dir) {
+            for dependency in path_dependencies(&manifest) {
+                to_visit.push(crate_dir.join(dependency));
+            }
+        }
+
+        found.push(crate_dir);
+    }
+
+    found
+}
+
+/// Gets every source file that the module depends on, including the sources of local path dependencies.
+/// Files within `target` or `.git` directories are skipped, since they are generated.
+///
+/// A build script can print `cargo:rerun-if-changed` for each of these to rebuild when the module changes.
+pub fn all_module_files(module_dir: PathBuf) -> Vec<String> {
+    local_crate_dirs(module_dir)
+        .into_iter()
+        .flat_map(|crate_dir| {
+            ["Cargo.toml", "build.rs", "src/**/*"]
+                .into_iter()
+                .flat_map(|pattern| glob_files(glob_pattern(&crate_dir, pattern)))
+                .filter(|file| {
+                    !Path::new(file)
+                        .strip_prefix(&crate_dir)
+                        .unwrap_or(Path::new(file))
+                        .components()
+                        .any(|component| {
+                            component.as_os_str() == "target" || component.as_os_str() == ".git"
+                        })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Finds the lock files deciding the versions of the module's dependencies: the module's own, or else that of
+/// the workspace it is a member of. Missing lock files are skipped, e.g. for a library without a committed lock.
+fn lock_files(options: &BuildOptions) -> Vec<String> {
+    let canonical = |dir: PathBuf| dir.canonicalize().unwrap_or(dir);
+    let module_dir = canonical(options.module_dir.clone());
+    let crate_dir = canonical(options.crate_dir());
+
+    let mut files = glob_files(glob_pattern(&crate_dir, "Cargo.lock"));
+    if files.is_empty() {
+        let workspace_dir = crate_dir.ancestors().skip(1).find(|dir| {
+            read_manifest(dir).is_some_and(|manifest| manifest.contains_key("workspace"))
+        });
+        if let Some(workspace_dir) = workspace_dir {
+            files.extend(glob_files(glob_pattern(workspace_dir, "Cargo.lock")));
+        }
+    }
+    // Cargo runs in the module directory, which may be a workspace holding the crate
+    if module_dir != crate_dir {
+        files.extend(glob_files(glob_pattern(&module_dir, "Cargo.lock")));
+    }
+    files
+}
+
+/// Gets the files that a build depends on, as [`all_module_files`] along with the module's lock file, other
+/// than those matching the `track_ignore` patterns of the options. Invalid patterns are skipped.
+pub fn module_files(options: &BuildOptions) -> Vec<String> {
+    let module_dir = options
+        .module_dir
+        .canonicalize()
+        .unwrap_or_else(|_| options.module_dir.clone());
+    let ignored = options
+        .track_ignore
+        .iter()
+        .filter_map(|pattern| glob::Pattern::new(&glob_pattern(&module_dir, pattern)).ok())
+        .collect::<Vec<_>>();
+
+    let mut files = all_module_files(options.crate_dir());
+    for lock_file in lock_files(options) {
+        if !files.contains(&lock_file) {
+            files.push(lock_file);
+        }
+    }
+    files
+        .into_iter()
+        .filter(|file| !ignored.iter().any(|pattern| pattern.matches(file)))
+        .collect()
+}
+
+/// Runs the `wasm-bindgen` CLI on a built module, returning the paths of the processed module and its JS glue.
+pub fn run_bindgen(output: &Path, module_dir: &Path) -> Result<(PathBuf, PathBuf), String> {
+    // e.g. "wasm-bindgen 0.2.92"
+    let Some(cli_version) = command_output_line(Command::new("wasm-bindgen").arg("--version"))
+    else {
+        return Err("`bindgen` requires the `wasm-bindgen` CLI - \
+            try running `cargo install wasm-bindgen-cli`"
+            .to_owned());
+    };
+    let cli_version = cli_version.trim_start_matches("wasm-bindgen").trim();
+
+    // The CLI only understands modules built against exactly the same version of the library
+    let lock = std::fs::read_to_string(module_dir.join("Cargo.lock"))
+        .ok()
+        .and_then(|lock| lock.parse::<toml::Table>().ok());
+    let guest_version = lock
+        .as_ref()
+        .and_then(|lock| lock.get("package"))
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+        .find(|package| package.get("name").and_then(toml::Value::as_str) == Some("wasm-bindgen"))
+        .and_then(|package| package.get("version"))
+        .and_then(toml::Value::as_str);
+    match guest_version {
+        None => {
+            return Err(format!(
+                "`bindgen` requires module `{}` to depend on `wasm-bindgen`",
+                module_dir.display()
+            ))
+        }
+        Some(guest_version) if !cli_version.starts_with(guest_version) => {
+            return Err(format!(
+                "the `wasm-bindgen` CLI is version {cli_version}, but module `{}` uses `wasm-bindgen` {guest_version} - \
+                try running `cargo install wasm-bindgen-cli --version {guest_version}`",
+                module_dir.display()
+            ))
+        }
+        Some(_) => {}
+    }
+
+    let out_dir = output
+        .parent()
+        .expect("output should be in a directory")
+        .join("bindgen");
+    let name = output
+        .file_stem()
+        .expect("output should be a file")
+        .to_string_lossy()
+        .to_string();
+    let out = Command::new("wasm-bindgen")
+        .args(["--target", "web", "--out-name", &name, "--out-dir"])
+        .arg(&out_dir)
+        .arg(output)
+        .output()
+        .map_err(|e| format!("failed to run `wasm-bindgen`: {e}"))?;
+    if !out.status.success() {
+        return Err(format!(
+            "`wasm-bindgen` failed with {}: \n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+
+    Ok((
+        out_dir.join(format!("{name}_bg.wasm")),
+        out_dir.join(format!("{name}.js")),
+    ))
+}
+
+/// Checks that a file exists and holds a WebAssembly module or component, for modules that were built
+/// ahead of time, returning its path.
+pub fn check_module_file(module: &Path) -> Result<PathBuf, String> {
+    let bytes = std::fs::read(module)
+        .map_err(|e| format!("failed to read module `{}`: {e}", module.display()))?;
+    if !wasmparser::Parser::is_core_wasm(&bytes) && !wasmparser::Parser::is_component(&bytes) {
+        return Err(format!(
+            "`{}` is not a WebAssembly module or component",
+            module.display()
+        ));
+    }
+
+    Ok(module.to_owned())
+}
+
+/// Computes the SHA-256 digest of some bytes, giving it in lowercase hex.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Checks that the SHA-256 digest of a built module is the one expected, given in hex.
+pub fn check_sha256(module: &Path, expected: &str) -> Result<(), String> {
+    let bytes = std::fs::read(module)
+        .map_err(|e| format!("failed to read module `{}`: {e}", module.display()))?;
+    let actual = sha256_hex(&bytes);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(format!(
+            "the SHA-256 digest of module `{}` is `{actual}`, but `{expected}` was expected - \
+            if the change is intended, update `expect_sha256`",
+            module.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// The limits of a module's memory, in WebAssembly pages, as read by [`read_memory`].
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLimits {
+    /// The initial size of the memory.
+    pub min_pages: u64,
+    /// The maximum size of the memory, if it has one.
+    pub max_pages: Option<u64>,
+}
+
+/// Reads the limits of the first memory of a built module, which is either imported or defined by it, or
+/// gives `None` if it has no memory.
+pub fn read_memory(output: &Path) -> Result<Option<MemoryLimits>, String> {
+    let bytes = std::fs::read(output)
+        .map_err(|e| format!("failed to read module `{}`: {e}", output.display()))?;
+
+    let limits = |memory: wasmparser::MemoryType| MemoryLimits {
+        min_pages: memory.initial,
+        max_pages: memory.maximum,
+    };
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        let payload =
+            payload.map_err(|e| format!("failed to parse module `{}`: {e}", output.display()))?;
+        match payload {
+            // Imported memories come first in the index space, so are found first
+            wasmparser::Payload::ImportSection(section) => {
+                for import in section.into_imports() {
+                    let import = import.map_err(|e| {
+                        format!("failed to parse module `{}`: {e}", output.display())
+                    })?;
+                    if let wasmparser::TypeRef::Memory(memory) = import.ty {
+                        return Ok(Some(limits(memory)));
+                    }
+                }
+            }
+            wasmparser::Payload::MemorySection(section) => {
+                if let Some(memory) = section.into_iter().next() {
+                    let memory = memory.map_err(|e| {
+                        format!("failed to parse module `{}`: {e}", output.display())
+                    })?;
+                    return Ok(Some(limits(memory)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+/// Lists the `(module, name)` pair of each import of a built module.
+pub fn read_imports(output: &Path) -> Result<Vec<(String, String)>, String> {
+    let bytes = std::fs::read(output)
+        .map_err(|e| format!("failed to read module `{}`: {e}", output.display()))?;
+
+    let mut imports = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        let payload =
+            payload.map_err(|e| format!("failed to parse module `{}`: {e}", output.display()))?;
+        if let wasmparser::Payload::ImportSection(section) = payload {
+            for import in section.into_imports() {
+                let import = import
+                    .map_err(|e| format!("failed to parse module `{}`: {e}", output.display()))?;
+                imports.push((import.module.to_owned(), import.name.to_owned()));
+            }
+        }
+    }
+
+    Ok(imports)
+}
+
+/// The WIT world of a built component, as read by [`read_world`].
+#[derive(Debug, Clone)]
+pub struct ComponentWorld {
+    /// The world in the WIT text format, as printed by `wasm-tools component wit`.
+    pub wit: String,
+    /// The name of each export of the component, e.g. `wasi:cli/run@0.2.0`.
+    pub exports: Vec<String>,
+    /// The name of each import of the component.
+    pub imports: Vec<String>,
+}
+
+/// Reads the WIT world of a built component, printing it with `wasm-tools component wit` and listing the
+/// names of its top-level exports and imports.
+pub fn read_world(component: &Path) -> Result<ComponentWorld, String> {
+    let out = Command::new("wasm-tools")
+        .args(["component", "wit"])
+        .arg(component)
+        .output()
+        .map_err(|e| {
+            format!(
+                "failed to run `wasm-tools`: {e} - \
+                `emit_world` requires the `wasm-tools` CLI, try running `cargo install wasm-tools`"
+            )
+        })?;
+    if !out.status.success() {
+        return Err(format!(
+            "`wasm-tools component wit` failed with {}: \n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+    let wit = String::from_utf8(out.stdout)
+        .map_err(|e| format!("`wasm-tools component wit` printed invalid UTF-8: {e}"))?;
+
+    let bytes = std::fs::read(component)
+        .map_err(|e| format!("failed to read component `{}`: {e}", component.display()))?;
+
+    let mut exports = Vec::new();
+    let mut imports = Vec::new();
+    // Nested modules and components are also parsed, and their sections are skipped
+    let mut depth = 0usize;
+    for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+        let payload = payload
+            .map_err(|e| format!("failed to parse component `{}`: {e}", component.display()))?;
+        match payload {
+            wasmparser::Payload::Version { .. } => depth += 1,
+            wasmparser::Payload::End(_) => depth -= 1,
+            wasmparser::Payload::ComponentExportSection(section) if depth == 1 => {
+                for export in section {
+                    let export = export.map_err(|e| {
+                        format!("failed to parse component `{}`: {e}", component.display())
+                    })?;
+                    exports.push(export.name.full_name().into_owned());
+                }
+            }
+            wasmparser::Payload::ComponentImportSection(section) if depth == 1 => {
+                for import in section {
+                    let import = import.map_err(|e| {
+                        format!("failed to parse component `{}`: {e}", component.display())
+                    })?;
+                    imports.push(import.name.full_name().into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(ComponentWorld {
+        wit,
+        exports,
+        imports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMAND: &str = "cargo +nightly build";
+
+    fn module_options() -> BuildOptions {
+        BuildOptions {
+            module_dir: "path/to/module".into(),
+            ..BuildOptions::default()
+        }
+    }
+
+    /// Gives the value that a command sets an environment variable to, if it sets it.
+    fn command_env(command: &Command, key: &str) -> Option<String> {
+        command
+            .get_envs()
+            .find(|(name, _)| *name == key)
+            .and_then(|(_, value)| value)
+            .map(|value| value.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn target_features_are_split_from_rustflags() {
+        let (flags, features) = split_target_features(
+            "-Ctarget-feature=+simd128, --cfg foo -C target-feature=,+atomics -C opt-level=s --codegen target-feature=-sign-ext"
+                .split_whitespace(),
+        );
+        assert_eq!(flags, ["--cfg", "foo", "-C", "opt-level=s"]);
+        assert_eq!(features, ["+simd128", "+atomics", "-sign-ext"]);
+
+        // A trailing `-C` is left for rustc to report
+        let (flags, features) = split_target_features(["-C"]);
+        assert_eq!(flags, ["-C"]);
+        assert!(features.is_empty());
+    }
+
+    #[test]
+    fn lib_only_modules_suggest_a_cdylib_crate_type() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"lib-only\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[lib]\n",
+        )
+        .unwrap();
+
+        let message = build(&BuildOptions {
+            module_dir: dir.path().to_owned(),
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        assert!(message.contains("has no `cdylib` or `bin` target"));
+        assert!(message.contains("try adding `crate-type = [\"cdylib\"]`"));
+
+        // A binary target produces a `.wasm` file too
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        let manifest = std::fs::read_to_string(dir.path().join("Cargo.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(check_produces_wasm(&manifest, dir.path()).is_ok());
+    }
+
+    #[test]
+    fn leb128_round_trips() {
+        for (value, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (624485, &[0xe5, 0x8e, 0x26]),
+            (u32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ] {
+            let mut bytes = Vec::new();
+            write_leb128_u32(&mut bytes, value);
+            assert_eq!(bytes, encoded);
+            assert_eq!(read_leb128_u32(&bytes), Some((value, encoded.len())));
+        }
+        assert_eq!(read_leb128_u32(&[0x80, 0x80]), None);
+    }
+
+    #[test]
+    fn custom_sections_are_appended() {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        append_custom_section(&mut module, "name", b"payload");
+        assert_eq!(module, b"\0asm\x01\0\0\0\0\x0c\x04namepayload");
+        wasmparser::Validator::new().validate_all(&module).unwrap();
+    }
+
+    #[test]
+    fn metadata_section_can_be_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path().join("module.wasm");
+        // A module with a single memory
+        std::fs::write(&module, b"\0asm\x01\0\0\0\x05\x03\x01\0\x01").unwrap();
+
+        add_metadata_section(&module, dir.path(), "+bulk-memory", "release", None).unwrap();
+        let bytes = std::fs::read(&module).unwrap();
+        wasmparser::Validator::new().validate_all(&bytes).unwrap();
+
+        let metadata = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .find_map(|payload| match payload.unwrap() {
+                wasmparser::Payload::CustomSection(section)
+                    if section.name() == METADATA_SECTION_NAME =>
+                {
+                    Some(serde_json::from_slice::<serde_json::Value>(section.data()).unwrap())
+                }
+                _ => None,
+            })
+            .expect("module should have a metadata section");
+        assert_eq!(metadata["features"], "+bulk-memory");
+        assert_eq!(metadata["profile"], "release");
+        assert!(metadata["timestamp"]
+            .as_u64()
+            .is_some_and(|timestamp| timestamp > 0));
+    }
+
+    #[test]
+    fn missing_toolchain_suggests_installing_nightly() {
+        let stderr = "error: toolchain 'nightly-x86_64-unknown-linux-gnu' is not installed\n";
+        let message = map_build_error(b"", stderr, &module_options(), COMMAND);
+        assert!(message.starts_with(
+            "failed to build module `path/to/module`: the `nightly` toolchain is not installed - \
+            try running `rustup toolchain install nightly`"
+        ));
+        assert!(message.contains("`-Z build-std`"));
+    }
+
+    #[test]
+    fn missing_target_suggests_adding_it() {
+        let stderr = "error[E0463]: can't find crate for `core`\n  |\n  = note: the `wasm32-unknown-unknown` \
+            target may not be installed\n";
+        let message = map_build_error(b"", stderr, &module_options(), COMMAND);
+        assert!(message.starts_with(
+            "failed to build module `path/to/module`: the `wasm32-unknown-unknown` target is not installed - \
+            try running `rustup target add wasm32-unknown-unknown --toolchain nightly`\n"
+        ));
+    }
+
+    #[test]
+    fn nested_workspace_suggests_an_empty_workspace() {
+        let stderr = "error: current package believes it's in a workspace when it's not:\n\
+            current:   /path/to/module/Cargo.toml\n\
+            workspace: /path/to/Cargo.toml\n";
+        let message = map_build_error(b"", stderr, &module_options(), COMMAND);
+        assert!(message.starts_with(
+            "failed to build module `path/to/module`: the module is nested within another workspace \
+            without being a member of it - try adding an empty `[workspace]` table to the module's `Cargo.toml`\n"
+        ));
+    }
+
+    #[test]
+    fn other_errors_are_shown_verbatim() {
+        let stderr = "error[E0425]: cannot find value `x` in this scope\n";
+        let stdout =
+            b"{\"reason\":\"build-finished\",\"success\":false}\nprinted by a build script\n";
+        let message = map_build_error(stdout, stderr, &module_options(), COMMAND);
+        assert_eq!(
+            message,
+            format!(
+                "failed to build module `path/to/module`: \nrunning `{COMMAND}`\n{stderr}\n\
+                stdout:\nprinted by a build script"
+            )
+        );
+    }
+
+    #[test]
+    fn lto_and_codegen_units_are_passed_as_rustflags() {
+        let command = build_command(&BuildOptions {
+            lto: Some("fat".to_owned()),
+            codegen_units: Some(1),
+            ..module_options()
+        })
+        .unwrap();
+        let rustflags = command_env(&command, "RUSTFLAGS").unwrap();
+        assert!(rustflags.contains("-C lto=fat"));
+        assert!(rustflags.contains("-C codegen-units=1"));
+
+        let command = build_command(&module_options()).unwrap();
+        let rustflags = command_env(&command, "RUSTFLAGS").unwrap();
+        assert!(!rustflags.contains("-C lto"));
+        assert!(!rustflags.contains("-C codegen-units"));
+    }
+
+    #[test]
+    fn inherited_encoded_rustflags_are_removed() {
+        let command = build_command(&module_options()).unwrap();
+        assert!(command
+            .get_envs()
+            .any(|(key, value)| key == "CARGO_ENCODED_RUSTFLAGS" && value.is_none()));
+    }
+
+    #[test]
+    fn encoded_rustflags_are_merged() {
+        let command = build_command(&BuildOptions {
+            features: TargetFeatures {
+                bulk_memory: true,
+                ..TargetFeatures::default()
+            },
+            env_vars: vec![(
+                "CARGO_ENCODED_RUSTFLAGS".to_owned(),
+                "--cfg\x1ffoo\x1f-Ctarget-feature=+sign-ext".to_owned(),
+            )],
+            ..module_options()
+        })
+        .unwrap();
+        let rustflags = command_env(&command, "RUSTFLAGS").unwrap();
+        assert!(rustflags.contains("--cfg foo"));
+        assert!(rustflags.contains("+bulk-memory"));
+        assert!(rustflags.contains("+sign-ext"));
+        assert!(!rustflags.contains('\x1f'));
+
+        let message = build_command(&BuildOptions {
+            env_vars: vec![(
+                "CARGO_ENCODED_RUSTFLAGS".to_owned(),
+                "--cfg\x1ffeature=\"a b\"".to_owned(),
+            )],
+            ..module_options()
+        })
+        .unwrap_err();
+        assert!(message.contains("contains whitespace"));
+    }
+
+    #[test]
+    fn machine_dependent_sections_are_removed() {
+        let mut module = b"\0asm\x01\0\0\0\x05\x03\x01\0\x01".to_vec();
+        let stripped = module.clone();
+        append_custom_section(&mut module, "producers", b"rustc 1.0.0");
+        append_custom_section(&mut module, "name", b"\0\x04host");
+        append_custom_section(&mut module, ".debug_info", b"/home/user/module");
+        append_custom_section(&mut module, "target_features", b"\x01+\x0bbulk-memory");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("module.wasm");
+        std::fs::write(&path, &module).unwrap();
+        make_reproducible(&path).unwrap();
+        let mut expected = stripped;
+        append_custom_section(&mut expected, "target_features", b"\x01+\x0bbulk-memory");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        // A section claiming more bytes than are left
+        assert_eq!(
+            remove_custom_sections(b"\0asm\x01\0\0\0\0\x06\x04name", |_| true),
+            Err("failed to read sections of malformed module".to_owned())
+        );
+        assert!(remove_custom_sections(b"\0wasm", |_| true).is_err());
+    }
+
+    #[test]
+    fn missing_module_directory_and_manifest_are_told_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let message = build(&BuildOptions {
+            module_dir: missing.clone(),
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            message,
+            format!("module directory `{}` does not exist", missing.display())
+        );
+
+        let message = build(&BuildOptions {
+            module_dir: dir.path().to_owned(),
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            message,
+            format!(
+                "target directory `{}` does not contain a `Cargo.toml` file",
+                dir.path().display()
+            )
+        );
+    }
+
+    #[test]
+    fn failing_build_scripts_are_called_out() {
+        let stderr =
+            "error: failed to run custom build command for `module v0.1.0 (/path/to/module)`\n";
+        let message = map_build_error(b"", stderr, &module_options(), COMMAND);
+        assert!(message.starts_with(
+            "failed to build module `path/to/module`: the build script of `module v0.1.0 (/path/to/module)` failed"
+        ));
+        assert!(message.ends_with(stderr));
+    }
+
+    #[test]
+    fn rustc_bootstrap_drops_the_nightly_toolchain() {
+        let toolchain_args = |command: &Command| {
+            command
+                .get_args()
+                .filter(|arg| arg.to_string_lossy().starts_with('+'))
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        if std::env::var_os(RUSTC_BOOTSTRAP).is_none() {
+            let command = build_command(&module_options()).unwrap();
+            assert_eq!(toolchain_args(&command), ["+nightly"]);
+        }
+
+        let command = build_command(&BuildOptions {
+            env_vars: vec![(RUSTC_BOOTSTRAP.to_owned(), "1".to_owned())],
+            ..module_options()
+        })
+        .unwrap();
+        assert!(toolchain_args(&command).is_empty());
+        assert!(command
+            .get_args()
+            .any(|arg| arg.to_string_lossy().starts_with("build-std")));
+        assert_eq!(command_env(&command, RUSTC_BOOTSTRAP).as_deref(), Some("1"));
+
+        // An explicit toolchain is still used
+        let command = build_command(&BuildOptions {
+            env_vars: vec![(RUSTC_BOOTSTRAP.to_owned(), "1".to_owned())],
+            toolchain: Some("stable".to_owned()),
+            ..module_options()
+        })
+        .unwrap();
+        assert_eq!(toolchain_args(&command), ["+stable"]);
+    }
+
+    #[test]
+    fn z_flags_are_passed_as_rustflags() {
+        let command = build_command(&BuildOptions {
+            z_flags: vec![
+                "wasm-c-abi=spec".to_owned(),
+                "location-detail=none".to_owned(),
+            ],
+            ..module_options()
+        })
+        .unwrap();
+        let rustflags = command_env(&command, "RUSTFLAGS").unwrap();
+        assert!(rustflags.contains("-Z wasm-c-abi=spec -Z location-detail=none"));
+    }
+
+    #[test]
+    fn old_editions_need_a_resolver() {
+        let check =
+            |manifest: &str| check_edition(&manifest.parse().unwrap(), Path::new("path/to/module"));
+
+        let message = check("[package]\nname = \"module\"\n").unwrap_err();
+        assert!(message.starts_with(
+            "module `path/to/module` has no `edition`, so defaults to the 2015 edition without choosing a resolver"
+        ));
+        let message = check("[package]\nedition = \"2018\"\n").unwrap_err();
+        assert!(message.starts_with(
+            "module `path/to/module` uses the 2018 edition without choosing a resolver"
+        ));
+        assert!(message.ends_with("or set `skip_manifest_checks: true`"));
+
+        assert!(check("[package]\nedition = \"2018\"\nresolver = \"2\"\n").is_ok());
+        assert!(check("[package]\nedition = \"2018\"\n\n[workspace]\nresolver = \"2\"\n").is_ok());
+        assert!(check("[package]\nedition = \"2021\"\n").is_ok());
+        assert!(check("[package]\nedition.workspace = true\n").is_ok());
+        assert!(check("[workspace]\nmembers = [\"module\"]\n").is_ok());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn glob_patterns_use_forward_slashes() {
+        assert_eq!(
+            glob_pattern(Path::new(r"C:\module\target\"), "**/*.wasm"),
+            "C:/module/target/**/*.wasm"
+        );
+    }
+
+    #[test]
+    fn glob_patterns_escape_the_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("target [x]*");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("module.wasm"), b"").unwrap();
+        std::fs::write(dir.join("module.d"), b"").unwrap();
+
+        let pattern = glob_pattern(&dir, "*.wasm");
+        assert!(pattern.ends_with("/target [[]x[]][*]/*.wasm"));
+        assert_eq!(
+            glob_files(pattern)
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>(),
+            [dir.join("module.wasm")]
+        );
+    }
+
+    #[test]
+    fn multivalue_and_tail_call_reach_rustc() {
+        let features = TargetFeatures {
+            multivalue: true,
+            tail_call: true,
+            ..TargetFeatures::default()
+        };
+        assert_eq!(features.to_string(), "+multivalue,+tail-call,");
+
+        let command = build_command(&BuildOptions {
+            features,
+            ..module_options()
+        })
+        .unwrap();
+        let rustflags = command_env(&command, "RUSTFLAGS").unwrap();
+        // The trailing comma is dropped, so that rustc isn't given an empty feature
+        assert!(rustflags
+            .split(' ')
+            .any(|flag| flag == "target-feature=+multivalue,+tail-call"));
+    }
+
+    #[test]
+    fn intermediate_artifacts_are_not_outputs() {
+        // The layout of a module with an example, built for `wasm32-unknown-unknown`
+        let dir = tempfile::tempdir().unwrap();
+        let profile_output = dir.path().join("wasm32-unknown-unknown/release/");
+        for file in [
+            "module.wasm",
+            "deps/module.wasm",
+            "deps/module-0123456789abcdef.wasm",
+            "examples/example.wasm",
+            "examples/example-0123456789abcdef.wasm",
+            "build/module-0123456789abcdef/build_script_build.wasm",
+            "incremental/module-0123456789abcdef/s-0123456789-abcdef.wasm",
+        ] {
+            let path = profile_output.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let outputs = |pattern| {
+            glob::glob(&glob_pattern(&profile_output, pattern))
+                .unwrap()
+                .map(Result::unwrap)
+                .filter(|path| !is_intermediate(path, &profile_output))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(outputs("*.wasm"), [profile_output.join("module.wasm")]);
+        assert_eq!(outputs("**/*.wasm"), [profile_output.join("module.wasm")]);
+    }
+
+    #[test]
+    fn sha256_mismatch_reports_the_computed_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let module = dir.path().join("module.wasm");
+        std::fs::write(&module, b"\0asm\x01\0\0\0").unwrap();
+        let actual = "93a44bbb96c751218e4c00d479e4c14358122a389acca16205b1e4d0dc5f9476";
+
+        assert_eq!(check_sha256(&module, actual), Ok(()));
+        assert_eq!(check_sha256(&module, &actual.to_uppercase()), Ok(()));
+        let expected = "0".repeat(64);
+        let err = check_sha256(&module, &expected).unwrap_err();
+        assert!(err.contains(&format!("is `{actual}`, but `{expected}` was expected")));
+    }
+
+    #[test]
+    fn modules_cannot_include_themselves() {
+        let host_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+        let message = build(&BuildOptions {
+            module_dir: host_dir.clone(),
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            message,
+            format!(
+                "module `{}` is the crate being compiled, which can't include itself",
+                host_dir.display()
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn modules_cannot_include_themselves_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("host");
+        std::os::unix::fs::symlink(std::env::var_os("CARGO_MANIFEST_DIR").unwrap(), &link).unwrap();
+        let message = build(&BuildOptions {
+            module_dir: link,
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        assert!(message.ends_with("is the crate being compiled, which can't include itself"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_loops_are_tracked_once() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        std::os::unix::fs::symlink("..", dir.path().join("src/loop")).unwrap();
+
+        let files = glob_files(glob_pattern(dir.path(), "src/**/*"));
+        assert_eq!(files, [dir.path().join("src/lib.rs").to_str().unwrap()]);
+    }
+
+    #[test]
+    fn dependency_updates_change_the_fingerprint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Lock files are found by canonical paths
+        let dir = temp_dir.path().canonicalize().unwrap();
+        std::fs::create_dir(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"guest\"\nversion = \"0.1.0\"\n\n[dependencies]\ndep = \"1\"\n",
+        )
+        .unwrap();
+        let options = BuildOptions {
+            module_dir: dir.clone(),
+            ..BuildOptions::default()
+        };
+        let lock_file = dir.join("Cargo.lock").to_str().unwrap().to_owned();
+
+        // A missing lock file isn't tracked
+        assert!(!module_files(&options).contains(&lock_file));
+        let unlocked = build_fingerprint(COMMAND, None, &options);
+
+        let lock = |version| {
+            format!("version = 3\n\n[[package]]\nname = \"dep\"\nversion = \"{version}\"\n")
+        };
+        std::fs::write(&lock_file, lock("1.0.0")).unwrap();
+        assert!(module_files(&options).contains(&lock_file));
+        let locked = build_fingerprint(COMMAND, None, &options);
+        assert_ne!(locked, unlocked);
+
+        std::fs::write(&lock_file, lock("1.0.1")).unwrap();
+        assert_ne!(build_fingerprint(COMMAND, None, &options), locked);
+    }
+
+    #[test]
+    fn workspace_lock_files_are_tracked_for_members() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Lock files are found by canonical paths
+        let dir = temp_dir.path().canonicalize().unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"guest\"]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("Cargo.lock"), "version = 3\n").unwrap();
+        std::fs::create_dir_all(dir.join("guest/src")).unwrap();
+        std::fs::write(dir.join("guest/src/lib.rs"), "").unwrap();
+        std::fs::write(
+            dir.join("guest/Cargo.toml"),
+            "[package]\nname = \"guest\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+
+        let files = module_files(&BuildOptions {
+            module_dir: dir.join("guest"),
+            ..BuildOptions::default()
+        });
+        assert!(files.contains(&dir.join("Cargo.lock").to_str().unwrap().to_owned()));
+    }
+
+    #[test]
+    fn memory64_builds_for_wasm64() {
+        let memory64 = TargetFeatures {
+            memory64: true,
+            ..TargetFeatures::default()
+        };
+        let command = build_command(&BuildOptions {
+            target: Some("wasm64-unknown-unknown".to_owned()),
+            features: memory64.clone(),
+            ..module_options()
+        })
+        .unwrap();
+        assert!(command
+            .get_args()
+            .collect::<Vec<_>>()
+            .windows(2)
+            .any(|args| args == ["--target", "wasm64-unknown-unknown"]));
+        let rustflags = command_env(&command, "RUSTFLAGS").unwrap();
+        assert!(rustflags.contains("+memory64"));
+
+        let message = build_command(&BuildOptions {
+            features: memory64,
+            ..module_options()
+        })
+        .unwrap_err();
+        assert!(message.starts_with(
+            "the `memory64` feature requires a 64-bit target, but the target is `wasm32-unknown-unknown`"
+        ));
+    }
+
+    #[test]
+    fn empty_packages_have_no_target() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"empty\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+
+        let message = build(&BuildOptions {
+            module_dir: dir.path().to_owned(),
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        assert_eq!(
+            message,
+            format!(
+                "module `{}` has no library or binary target, as it has neither a `src/lib.rs` nor a `src/main.rs` - \
+                check that the module's path points at the directory of the crate to build",
+                dir.path().display()
+            )
+        );
+
+        // Explicit targets are left for cargo to check
+        let manifest = "[package]\nname = \"empty\"\n\n[lib]\npath = \"lib.rs\"\n"
+            .parse()
+            .unwrap();
+        assert!(check_has_target(&manifest, dir.path()).is_ok());
+    }
+
+    #[test]
+    fn feature_checks_depend_on_the_target() {
+        let atomics = TargetFeatures {
+            atomics: true,
+            ..TargetFeatures::default()
+        };
+        assert_eq!(
+            atomics.check("wasm64-unknown-unknown").unwrap_err(),
+            "the `atomics` feature requires `bulk_memory` on `wasm64-unknown-unknown`, since shared memories can \
+            only be initialised with passive data segments - try enabling `bulk_memory`, or set `skip_feature_checks: true`"
+        );
+
+        let atomics = TargetFeatures {
+            bulk_memory: true,
+            ..atomics
+        };
+        assert!(atomics.check("wasm32-unknown-unknown").is_ok());
+        assert!(atomics.check("wasm32-wasip1-threads").is_ok());
+        assert_eq!(
+            atomics.check("wasm32-wasip1").unwrap_err(),
+            "the `atomics` feature requires a target with threads, but the target is `wasm32-wasip1` - \
+            try setting `target: \"wasm32-wasip1-threads\"`, or set `skip_feature_checks: true`"
+        );
+        let disabled = TargetFeatures {
+            disabled: vec!["atomics".to_owned()],
+            ..atomics
+        };
+        assert!(disabled.check("wasm32-wasip1").is_ok());
+
+        let memory64 = TargetFeatures {
+            memory64: true,
+            ..TargetFeatures::default()
+        };
+        assert!(memory64.check("wasm64-unknown-unknown").is_ok());
+        assert!(memory64.check("wasm32-wasip2").is_err());
+        assert!(memory64.check("path/to/custom.json").is_ok());
+    }
+
+    #[test]
+    fn toolchain_versions_change_the_fingerprint() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let options = BuildOptions {
+            module_dir: dir.to_owned(),
+            ..BuildOptions::default()
+        };
+        let version = rustc_version(None, dir).expect("rustc should be installed");
+        assert!(version.starts_with("rustc "));
+        assert!(version.contains("\nhost: "));
+
+        // The command is the same, so only the versions of the toolchains can tell them apart
+        if rustc_version(Some("stable"), dir) != rustc_version(Some("nightly"), dir) {
+            assert_ne!(
+                build_fingerprint(COMMAND, Some("stable"), &options),
+                build_fingerprint(COMMAND, Some("nightly"), &options)
+            );
+        }
+        assert_eq!(
+            build_fingerprint(COMMAND, Some("nightly"), &options),
+            build_fingerprint(COMMAND, Some("nightly"), &options)
+        );
+    }
+
+    #[test]
+    fn long_env_target_dirs_are_hashed_with_a_warning() {
+        let options = BuildOptions {
+            env_vars: vec![("CONFIG".repeat(MAX_TARGET_DIR_LEN), "x".to_owned())],
+            ..module_options()
+        };
+        let plan = plan_build(&options).unwrap();
+        assert!(!plan.target_dir.contains("_CONFIG"));
+        assert!(plan.target_dir.contains("_env"));
+        assert_eq!(
+            build_warnings(&options),
+            [format!(
+                "the `env` of module `path/to/module` would make its target directory longer than \
+                {MAX_TARGET_DIR_LEN} characters, so it is hashed instead"
+            )]
+        );
+
+        let options = BuildOptions {
+            env_vars: vec![("CONFIG".to_owned(), "x".to_owned())],
+            ..module_options()
+        };
+        assert!(plan_build(&options)
+            .unwrap()
+            .target_dir
+            .contains("_CONFIG_"));
+        assert!(build_warnings(&options).is_empty());
+    }
+
+    #[test]
+    fn env_values_are_hashed_in_target_dirs() {
+        let target_dir = |value: &str| {
+            plan_build(&BuildOptions {
+                env_vars: vec![
+                    ("CONFIG".to_owned(), value.to_owned()),
+                    ("MODE".to_owned(), "fast".to_owned()),
+                ],
+                ..module_options()
+            })
+            .unwrap()
+            .target_dir
+        };
+
+        let json = target_dir(r#"{"names":["a","b"],"path":"../../.."}"#);
+        let (_, name) = json.rsplit_once('/').unwrap();
+        assert!(name.contains("_CONFIG_MODE_"));
+        assert!(!name.contains("names"));
+        assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+
+        assert_ne!(target_dir("a"), json);
+        assert_eq!(target_dir("a"), target_dir("a"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_templates_can_print_before_reading_their_input() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // More than a pipe holds in each direction
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("template.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\nhead -c 1048576 /dev/zero | tr '\\0' ' '\ncat > /dev/null\necho '[\"cargo\", \"build\"]'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let arg = "x".repeat(1024 * 1024);
+        assert_eq!(
+            apply_command_template(&script, &["cargo", &arg]).unwrap(),
+            ["cargo", "build"]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn spawn_failures_name_the_module_then_the_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"module\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[lib]\ncrate-type = [\"cdylib\"]\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+        // Runs a program that doesn't exist in place of cargo
+        let script = dir.path().join("template.sh");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncat > /dev/null\necho '[\"/nonexistent/cargo\", \"build\"]'\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let message = build(&BuildOptions {
+            module_dir: dir.path().to_owned(),
+            target_dir: Some(dir.path().join("target")),
+            command_template: Some(script),
+            ..BuildOptions::default()
+        })
+        .unwrap_err();
+        let (first, rest) = message.split_once('\n').unwrap();
+        assert_eq!(
+            first,
+            format!("failed to build module `{}`: ", dir.path().display())
+        );
+        assert!(rest.starts_with("running `"));
+        assert!(rest.contains("/nonexistent/cargo"));
+    }
+}
@@ -2,13 +2,24 @@
 //! by compiling it at build time of the invoking module.
 
 #![cfg_attr(feature = "proc_macro_span", feature(proc_macro_span))]
+#![cfg_attr(feature = "tracked_path", feature(proc_macro_tracked_path))]
+#![cfg_attr(feature = "tracked_env", feature(proc_macro_tracked_env))]
+#![cfg_attr(feature = "proc_macro_diagnostic", feature(proc_macro_diagnostic))]
 
-use std::{fmt::Display, path::PathBuf, process::Command, sync::Mutex};
+use std::path::{Path, PathBuf};
 
+use include_wasm_rs_build::{
+    build_command, build_warnings, check_module_file, check_sha256, debug_log, json_string,
+    module_files, read_imports, read_memory, read_world, run_bindgen, BuildOptions, InjectData,
+    InjectSource, TargetFeatures, CARGO_TARGET_DIR, RUSTC_BOOTSTRAP, WASM_PAGE_SIZE,
+};
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
 use syn::{parse::ParseStream, parse_macro_input, spanned::Spanned};
 
+/// Skips building the modules given `skip_in_debug` when set in the environment that the host is built in.
+const INCLUDE_WASM_RS_SKIP: &str = "INCLUDE_WASM_RS_SKIP";
+
 // Hacky polyfill for `proc_macro::Span::source_file`
 #[cfg(not(feature = "proc_macro_span"))]
 fn find_me(root: &str, pattern: &str) -> PathBuf {
@@ -18,6 +29,15 @@ fn find_me(root: &str, pattern: &str) -> PathBuf {
         .unwrap()
         .flatten()
     {
+        // Files in nested crates, e.g. workspace members or the modules themselves, can't invoke this crate's macros
+        let in_nested_crate = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| *dir != Path::new(root))
+            .any(|dir| dir.join("Cargo.toml").is_file());
+        if in_nested_crate {
+            continue;
+        }
         if let Ok(mut f) = std::fs::File::open(&path) {
             let mut contents = String::new();
             std::io::Read::read_to_string(&mut f, &mut contents).ok();
@@ -51,49 +71,57 @@ fn find_me(root: &str, pattern: &str) -> PathBuf {
     }
 }
 
-#[derive(Default)]
-struct TargetFeatures {
-    atomics: bool,
-    bulk_memory: bool,
-    mutable_globals: bool,
-}
-
-impl TargetFeatures {
-    fn from_list_of_exprs(
-        elems: syn::punctuated::Punctuated<syn::Expr, syn::Token![,]>,
-    ) -> syn::parse::Result<Self> {
-        let mut res = Self::default();
-
-        for elem in elems {
-            let span = elem.span();
-            let name = match elem {
-                syn::Expr::Path(ident)
-                    if ident.attrs.is_empty()
-                        && ident.qself.is_none()
-                        && ident.path.leading_colon.is_none()
-                        && ident.path.segments.len() == 1
-                        && ident.path.segments[0].arguments.is_empty() =>
-                {
-                    ident.path.segments[0].ident.to_string()
-                }
-                _ => {
-                    return Err(syn::Error::new(
-                        span,
-                        "expected a single token giving a feature",
-                    ))
-                }
-            };
+/// Parses a list of features, given as identifiers or strings.
+fn features_from_list_of_exprs(
+    elems: syn::punctuated::Punctuated<syn::Expr, syn::Token![,]>,
+) -> syn::parse::Result<TargetFeatures> {
+    let mut res = TargetFeatures::default();
 
-            match name.as_str() {
-                "atomics" => res.atomics = true,
-                "bulk_memory" => res.bulk_memory = true,
-                "mutable_globals" => res.mutable_globals = true,
-                _ => return Err(syn::Error::new(span, "unknown feature")),
+    for elem in elems {
+        let span = elem.span();
+        // A feature may be disabled with `!`
+        let (elem, enabled) = match elem {
+            syn::Expr::Unary(syn::ExprUnary {
+                attrs,
+                op: syn::UnOp::Not(_),
+                expr,
+            }) if attrs.is_empty() => (*expr, false),
+            elem => (elem, true),
+        };
+        let name = match elem {
+            syn::Expr::Path(ident)
+                if ident.attrs.is_empty()
+                    && ident.qself.is_none()
+                    && ident.path.leading_colon.is_none()
+                    && ident.path.segments.len() == 1
+                    && ident.path.segments[0].arguments.is_empty() =>
+            {
+                ident.path.segments[0].ident.to_string()
             }
-        }
+            // Strings may also use the hyphenated names that rustc uses
+            syn::Expr::Lit(syn::ExprLit {
+                attrs,
+                lit: syn::Lit::Str(name),
+            }) if attrs.is_empty() => name.value().replace('-', "_"),
+            _ => {
+                return Err(syn::Error::new(
+                    span,
+                    "expected a single token or string giving a feature",
+                ))
+            }
+        };
 
-        Ok(res)
+        let known = if enabled {
+            res.enable(&name)
+        } else {
+            res.disable(&name)
+        };
+        if !known {
+            return Err(syn::Error::new(span, "unknown feature"));
+        }
     }
+
+    Ok(res)
 }
 
 fn degroup_expr(expr: syn::Expr) -> syn::Expr {
@@ -107,12 +135,315 @@ fn degroup_expr(expr: syn::Expr) -> syn::Expr {
     }
 }
 
-#[derive(Default)]
+/// Gets the literal given as the value of an option, or an error saying that an `expected` literal should be given.
+fn expect_lit<'a>(expr: &'a syn::Expr, expected: &str) -> syn::parse::Result<&'a syn::Lit> {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit { attrs, lit }) if attrs.is_empty() => Ok(lit),
+        _ => Err(syn::Error::new(expr.span(), format!("expected {expected}"))),
+    }
+}
+
+/// Gets the value of a boolean option.
+fn parse_bool(expr: &syn::Expr) -> syn::parse::Result<bool> {
+    match expect_lit(expr, "boolean")? {
+        syn::Lit::Bool(value) => Ok(value.value),
+        _ => Err(syn::Error::new(expr.span(), "expected boolean")),
+    }
+}
+
+/// Gets the literal strings given as the elements of an array option.
+fn expect_str_array(expr: &syn::Expr) -> syn::parse::Result<Vec<syn::LitStr>> {
+    match expr {
+        syn::Expr::Array(syn::ExprArray {
+            attrs,
+            bracket_token: _,
+            elems,
+        }) if attrs.is_empty() => elems
+            .iter()
+            .map(|elem| match expect_lit(elem, "literal string")? {
+                syn::Lit::Str(elem) => Ok(elem.clone()),
+                _ => Err(syn::Error::new(elem.span(), "expected literal string")),
+            })
+            .collect(),
+        _ => Err(syn::Error::new(expr.span(), "expected array of strings")),
+    }
+}
+
+/// Serializes a structured env value to compact JSON.
+///
+/// Strings, integers, floats and bools map to their JSON equivalents, arrays map to JSON arrays,
+/// and struct literals (whose names are ignored) map to JSON objects.
+fn expr_to_json(expr: &syn::Expr) -> syn::parse::Result<String> {
+    let unrepresentable = || {
+        syn::Error::new(
+            expr.span(),
+            format!(
+                "expected a value representable as JSON, found `{}`",
+                expr.to_token_stream()
+            ),
+        )
+    };
+
+    match expr {
+        syn::Expr::Group(syn::ExprGroup { attrs, expr, .. }) if attrs.is_empty() => {
+            expr_to_json(expr)
+        }
+        syn::Expr::Lit(syn::ExprLit { attrs, lit }) if attrs.is_empty() => match lit {
+            syn::Lit::Str(v) => Ok(json_string(&v.value())),
+            syn::Lit::Int(i) => Ok(i.base10_digits().to_owned()),
+            syn::Lit::Float(f) => Ok(f.base10_digits().to_owned()),
+            syn::Lit::Bool(b) => Ok(b.value.to_string()),
+            _ => Err(unrepresentable()),
+        },
+        syn::Expr::Unary(syn::ExprUnary {
+            attrs,
+            op: syn::UnOp::Neg(_),
+            expr: inner,
+        }) if attrs.is_empty() => match expect_lit(inner, "number")? {
+            syn::Lit::Int(i) => Ok(format!("-{}", i.base10_digits())),
+            syn::Lit::Float(f) => Ok(format!("-{}", f.base10_digits())),
+            _ => Err(unrepresentable()),
+        },
+        syn::Expr::Array(syn::ExprArray { attrs, elems, .. }) if attrs.is_empty() => {
+            let elems = elems
+                .iter()
+                .map(expr_to_json)
+                .collect::<syn::parse::Result<Vec<_>>>()?;
+            Ok(format!("[{}]", elems.join(",")))
+        }
+        syn::Expr::Struct(syn::ExprStruct {
+            attrs,
+            fields,
+            dot2_token: None,
+            rest: None,
+            ..
+        }) if attrs.is_empty() => {
+            let fields = fields
+                .iter()
+                .map(|field| {
+                    let key = match &field.member {
+                        syn::Member::Named(name) => name.to_string(),
+                        syn::Member::Unnamed(_) => return Err(unrepresentable()),
+                    };
+                    Ok(format!(
+                        "{}:{}",
+                        json_string(&key),
+                        expr_to_json(&field.expr)?
+                    ))
+                })
+                .collect::<syn::parse::Result<Vec<_>>>()?;
+            Ok(format!("{{{}}}", fields.join(",")))
+        }
+        _ => Err(unrepresentable()),
+    }
+}
+
+/// Parses an option of a dict of options. Its value may also be a bare braced dict, e.g.
+/// `inject_data: { segment: 0, .. }`, which isn't an expression so is kept as tokens.
+fn parse_option(input: ParseStream) -> syn::parse::Result<syn::FieldValue> {
+    if !(input.peek(syn::Ident) && input.peek2(syn::Token![:]) && input.peek3(syn::token::Brace)) {
+        return input.parse();
+    }
+    let member = syn::Member::Named(input.parse()?);
+    let colon_token = Some(input.parse()?);
+    let group = input.parse::<proc_macro2::Group>()?;
+    Ok(syn::FieldValue {
+        attrs: Vec::new(),
+        member,
+        colon_token,
+        expr: syn::Expr::Verbatim(group.into_token_stream()),
+    })
+}
+
+/// Gets the name of an option given in a dict of options.
+fn option_name(value: &syn::FieldValue) -> String {
+    match &value.member {
+        syn::Member::Named(name) => name.to_string(),
+        syn::Member::Unnamed(unnamed) => unnamed.index.to_string(),
+    }
+}
+
+/// Converts a value read from a config file into the tokens giving the same value as the option `key`.
+///
+/// Tables map to bare braced dicts, or to `Env { .. }` for `env`, and strings map to identifiers for the
+/// options that take one, e.g. `embed = "inline"`.
+fn toml_to_tokens(key: &str, value: &toml::Value) -> Result<proc_macro2::TokenStream, String> {
+    match value {
+        toml::Value::String(value) if key == "embed" => {
+            let ident = syn::parse_str::<syn::Ident>(value)
+                .map_err(|_| format!("expected an identifier, found `{value}`"))?;
+            Ok(quote! { #ident })
+        }
+        toml::Value::String(value) => Ok(quote! { #value }),
+        toml::Value::Integer(value) => {
+            let magnitude = proc_macro2::Literal::u64_unsuffixed(value.unsigned_abs());
+            Ok(if *value < 0 {
+                quote! { -#magnitude }
+            } else {
+                quote! { #magnitude }
+            })
+        }
+        toml::Value::Float(value) if value.is_finite() => {
+            let magnitude = proc_macro2::Literal::f64_unsuffixed(value.abs());
+            Ok(if value.is_sign_negative() {
+                quote! { -#magnitude }
+            } else {
+                quote! { #magnitude }
+            })
+        }
+        toml::Value::Float(value) => Err(format!("unsupported float `{value}`")),
+        toml::Value::Boolean(value) => Ok(quote! { #value }),
+        toml::Value::Array(values) => {
+            let values = values
+                .iter()
+                .map(|value| toml_to_tokens(key, value))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(quote! { [#(#values),*] })
+        }
+        toml::Value::Table(table) => {
+            let (keys, values): (Vec<_>, Vec<_>) = table
+                .iter()
+                .map(|(field, value)| {
+                    let value = toml_to_tokens(field, value)?;
+                    let field = syn::parse_str::<syn::Ident>(field)
+                        .map_err(|_| format!("expected an identifier as a key, found `{field}`"))?;
+                    Ok((field, value))
+                })
+                .collect::<Result<Vec<_>, String>>()?
+                .into_iter()
+                .unzip();
+            Ok(if key == "env" {
+                quote! { Env { #(#keys: #values),* } }
+            } else {
+                quote! { { #(#keys: #values),* } }
+            })
+        }
+        toml::Value::Datetime(value) => Err(format!("unsupported datetime `{value}`")),
+    }
+}
+
+/// Reads the options in the config file given by the `config_file` option of a dict of options, other than
+/// those that the dict gives directly. Gives the resolved path of the file alongside the options.
+fn config_file_options(
+    config_file: &syn::FieldValue,
+    dict: &syn::punctuated::Punctuated<syn::FieldValue, syn::Token![,]>,
+) -> syn::parse::Result<(PathBuf, Vec<syn::FieldValue>)> {
+    let config_file = match expect_lit(&degroup_expr(config_file.expr.clone()), "literal string")? {
+        syn::Lit::Str(config_file) => config_file.clone(),
+        _ => {
+            return Err(syn::Error::new(
+                config_file.expr.span(),
+                "expected literal string",
+            ))
+        }
+    };
+    let span = config_file.span();
+
+    // A config file may be shared by many invocations, so the invoking file is found by the module's path where given
+    let path_arg = dict
+        .iter()
+        .filter(|value| matches!(option_name(value).as_str(), "path" | "prebuilt"))
+        .find_map(|value| match degroup_expr(value.expr.clone()) {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(path),
+                ..
+            }) => Some(path.value()),
+            _ => None,
+        })
+        .unwrap_or_else(|| config_file.value());
+    let invocation_dir =
+        invocation_dir(Path::new(&path_arg)).map_err(|e| syn::Error::new(span, e))?;
+    let path = resolve_path(&invocation_dir, Path::new(&config_file.value()));
+
+    let contents = std::fs::read_to_string(&path).map_err(|e| {
+        syn::Error::new(
+            span,
+            format!("failed to read config file `{}`: {e}", path.display()),
+        )
+    })?;
+    let table = contents.parse::<toml::Table>().map_err(|e| {
+        syn::Error::new(
+            span,
+            format!("failed to parse config file `{}`: {e}", path.display()),
+        )
+    })?;
+
+    let given = dict.iter().map(option_name).collect::<Vec<_>>();
+    let mut options = Vec::new();
+    for (key, value) in &table {
+        if matches!(key.as_str(), "path" | "prebuilt" | "config_file") {
+            return Err(syn::Error::new(
+                span,
+                format!("config file `{}` can't give `{key}`", path.display()),
+            ));
+        }
+        // Options given directly replace those in the file
+        if given.contains(key) {
+            continue;
+        }
+        let value = toml_to_tokens(key, value).map_err(|e| {
+            syn::Error::new(
+                span,
+                format!("in `{key}` of config file `{}`: {e}", path.display()),
+            )
+        })?;
+        if syn::parse_str::<syn::Ident>(key).is_err() {
+            return Err(syn::Error::new(
+                span,
+                format!("unknown option `{key}` in config file `{}`", path.display()),
+            ));
+        }
+        let key = syn::Ident::new(key, span);
+        // Errors in the options are reported at the `config_file` option
+        options.push(syn::parse::Parser::parse2(
+            parse_option,
+            quote::quote_spanned! { span=> #key: #value },
+        )?);
+    }
+
+    Ok((path, options))
+}
+
+#[derive(Clone, Default)]
 struct Args {
-    module_dir: PathBuf,
-    features: TargetFeatures,
-    env_vars: Vec<(String, String)>,
-    release: bool,
+    /// Everything that the build itself depends on.
+    options: BuildOptions,
+    dry_run: bool,
+    emit_file: Option<EmitFile>,
+    bindgen: bool,
+    emit_imports: bool,
+    emit_world: bool,
+    emit_len: bool,
+    emit_memory_info: bool,
+    sized: bool,
+    /// Embeds the module's bytes as a literal, rather than with `include_bytes!`.
+    embed_inline: bool,
+    report_size: bool,
+    /// The SHA-256 digest, in hex, that the included bytes must have.
+    expect_sha256: Option<String>,
+    skip_in_debug: bool,
+    /// A module built ahead of time, included instead of building one.
+    prebuilt: Option<PathBuf>,
+    /// The file that default options were read from, which the build depends on.
+    config_file: Option<PathBuf>,
+    /// The files that `env` values were read from, which the build depends on.
+    env_files: Vec<PathBuf>,
+    /// Variables read from the host environment while parsing, which the build depends on.
+    host_env_vars: Vec<String>,
+}
+
+impl Args {
+    /// Whether the macro expands to items, holding the module and constants describing it, rather than
+    /// an expression.
+    fn emits_items(&self) -> bool {
+        self.emit_imports || self.emit_world || self.emit_len || self.emit_memory_info
+    }
+
+    /// Whether the module is skipped, rather than built, because `skip_in_debug` is given and
+    /// `INCLUDE_WASM_RS_SKIP` is set.
+    fn is_skipped(&self) -> bool {
+        self.skip_in_debug && std::env::var_os(INCLUDE_WASM_RS_SKIP).is_some()
+    }
 }
 
 impl syn::parse::Parse for Args {
@@ -120,39 +451,62 @@ impl syn::parse::Parse for Args {
         // Just a string gives a path, with default options
         if input.peek(syn::LitStr) {
             let path = input.parse::<syn::LitStr>()?;
+            if path.value().is_empty() {
+                return Err(syn::Error::new(path.span(), "expected a non-empty path"));
+            }
+            // A trailing comma is allowed, but a comma between modules of `build_wasm_many!` is left for it
+            let rest = input.fork();
+            if rest.parse::<syn::Token![,]>().is_ok() && rest.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
             return Ok(Self {
-                module_dir: PathBuf::from(path.value()),
+                options: BuildOptions {
+                    module_dir: PathBuf::from(path.value()),
+                    ..BuildOptions::default()
+                },
+                host_env_vars: vec![RUSTC_BOOTSTRAP.to_owned(), CARGO_TARGET_DIR.to_owned()],
                 ..Self::default()
             });
         }
 
         // Else we expect a json-like dict of options
         let mut res = Self::default();
+        let mut inherit_env_prefixes = Vec::new();
+        // The `env` values to read from files once the invoking file is known, as indices into `env_vars`
+        let mut env_files = Vec::new();
+        let mut has_path = false;
 
-        let dict =
-            syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::parse_terminated(
+        let mut dict =
+            syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::parse_terminated_with(
                 input,
+                parse_option,
             )?;
+        // Options read from a config file come first, as defaults for those given directly
+        if let Some(config_file) = dict
+            .iter()
+            .find(|value| option_name(value) == "config_file")
+        {
+            let (config_file, defaults) = config_file_options(config_file, &dict)?;
+            res.config_file = Some(config_file);
+            dict = defaults.into_iter().chain(dict).collect();
+        }
         for mut value in dict {
             if !value.attrs.is_empty() {
                 return Err(syn::Error::new(value.attrs[0].span(), "unexpected element"));
             }
-            let name = match &value.member {
-                syn::Member::Named(name) => name.to_string(),
-                syn::Member::Unnamed(unnamed) => unnamed.index.to_string(),
-            };
+            let name = option_name(&value);
 
             value.expr = degroup_expr(value.expr);
 
             // Parse value depending on key
             match name.as_str() {
                 "path" => {
-                    // String as PathBuf
-                    res.module_dir = match value.expr {
-                        syn::Expr::Lit(syn::ExprLit {
-                            attrs,
-                            lit: syn::Lit::Str(path),
-                        }) if attrs.is_empty() => PathBuf::from(path.value()),
+                    // Non-empty string as PathBuf
+                    res.options.module_dir = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(path) if path.value().is_empty() => {
+                            return Err(syn::Error::new(path.span(), "expected a non-empty path"))
+                        }
+                        syn::Lit::Str(path) => PathBuf::from(path.value()),
                         _ => {
                             return Err(syn::Error::new(
                                 value.expr.span(),
@@ -160,402 +514,2377 @@ impl syn::parse::Parse for Args {
                             ))
                         }
                     };
+                    has_path = true;
                 }
-                "release" => {
-                    // Boolean
-                    res.release = match value.expr {
-                        syn::Expr::Lit(syn::ExprLit {
-                            attrs,
-                            lit: syn::Lit::Bool(release),
-                        }) if attrs.is_empty() => release.value,
-                        _ => return Err(syn::Error::new(value.expr.span(), "expected boolean")),
+                "prebuilt" => {
+                    // String as PathBuf
+                    res.prebuilt = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(prebuilt) => Some(PathBuf::from(prebuilt.value())),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
                     };
                 }
-                "features" => {
-                    // Array of identifiers
-                    match value.expr {
-                        syn::Expr::Array(syn::ExprArray {
-                            attrs,
-                            bracket_token: _,
-                            elems,
-                        }) if attrs.is_empty() => {
-                            res.features = TargetFeatures::from_list_of_exprs(elems)?
+                "manifest_path" => {
+                    // String as PathBuf, naming a `Cargo.toml`
+                    res.options.manifest_path = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(manifest_path)
+                            if Path::new(&manifest_path.value()).file_name()
+                                == Some("Cargo.toml".as_ref()) =>
+                        {
+                            Some(PathBuf::from(manifest_path.value()))
+                        }
+                        syn::Lit::Str(manifest_path) => {
+                            return Err(syn::Error::new(
+                                manifest_path.span(),
+                                "expected a path to a `Cargo.toml` file",
+                            ))
+                        }
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
                         }
-                        _ => return Err(syn::Error::new(value.expr.span(), "expected boolean")),
                     };
                 }
-                "env" => {
-                    // Dictionary of key value pairs
-                    match value.expr {
-                        syn::Expr::Struct(syn::ExprStruct {
-                            attrs,
-                            qself: None,
-                            path:
-                                syn::Path {
-                                    leading_colon: None,
-                                    segments,
-                                },
-                            brace_token: _,
-                            fields,
-                            dot2_token: None,
-                            rest: None,
-                        }) if attrs.is_empty()
-                            && segments.len() == 1
-                            && segments[0].arguments.is_empty()
-                            && segments[0].ident == "Env" =>
+                "release" => {
+                    // Boolean
+                    res.options.release = parse_bool(&value.expr)?;
+                }
+                "inject_data" => {
+                    // Braced dict of a segment index and a source of bytes, optionally named `InjectData`
+                    let expected = || {
+                        syn::Error::new(
+                            value.expr.span(),
+                            "expected `{ segment: .., bytes_from_env: .. }`",
+                        )
+                    };
+                    let fields = match &value.expr {
+                        syn::Expr::Struct(data)
+                            if data.path.is_ident("InjectData") && data.rest.is_none() =>
                         {
-                            for field in fields {
-                                let span = field.span();
-                                if !field.attrs.is_empty() || field.colon_token.is_none() {
-                                    return Err(syn::Error::new(span, "expected key value pair"));
+                            data.fields.clone()
+                        }
+                        syn::Expr::Verbatim(tokens) => {
+                            let group = syn::parse2::<proc_macro2::Group>(tokens.clone())?;
+                            syn::parse::Parser::parse2(
+                                syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::parse_terminated,
+                                group.stream(),
+                            )?
+                        }
+                        _ => return Err(expected()),
+                    };
+                    let mut segment = None;
+                    let mut source = None;
+                    for field in &fields {
+                        let expr = degroup_expr(field.expr.clone());
+                        match option_name(field).as_str() {
+                            "segment" => {
+                                // Integer index. Segments are only named in the optional `name` section,
+                                // which e.g. `reproducible` removes, so they can't be chosen by name.
+                                if matches!(
+                                    &expr,
+                                    syn::Expr::Path(_)
+                                        | syn::Expr::Lit(syn::ExprLit {
+                                            lit: syn::Lit::Str(_),
+                                            ..
+                                        })
+                                ) {
+                                    return Err(syn::Error::new(
+                                        expr.span(),
+                                        "data segments can't be chosen by name, expected the index of the segment",
+                                    ));
                                 }
-
-                                let env_name = match &field.member {
-                                    syn::Member::Named(name) => name.to_string(),
+                                segment = match expect_lit(&expr, "integer")? {
+                                    syn::Lit::Int(index) => Some(index.base10_parse::<u32>()?),
                                     _ => {
                                         return Err(syn::Error::new(
-                                            span,
-                                            "expected env variable name",
+                                            expr.span(),
+                                            "expected integer",
                                         ))
                                     }
                                 };
-
-                                let mut expr = &field.expr;
-                                while let syn::Expr::Group(syn::ExprGroup {
-                                    attrs,
-                                    group_token: _,
-                                    expr: inner_expr,
-                                }) = expr
-                                {
-                                    if !attrs.is_empty() {
+                            }
+                            "bytes_from_env" => {
+                                // String naming a host environment variable
+                                let name = match expect_lit(&expr, "literal string")? {
+                                    syn::Lit::Str(name) => name.value(),
+                                    _ => {
                                         return Err(syn::Error::new(
-                                            attrs[0].span(),
-                                            "expected a string, int, float or bool",
-                                        ));
+                                            expr.span(),
+                                            "expected literal string",
+                                        ))
                                     }
-
-                                    expr = inner_expr;
-                                }
-
-                                let env_val = match expr {
-                                    syn::Expr::Lit(syn::ExprLit { attrs, lit })
-                                        if attrs.is_empty() =>
-                                    {
-                                        match lit {
-                                            syn::Lit::Str(v) => v.value(),
-                                            syn::Lit::Int(i) => i.to_string(),
-                                            syn::Lit::Float(f) => f.to_string(),
-                                            syn::Lit::Bool(b) => b.value.to_string(),
-                                            _ => {
-                                                return Err(syn::Error::new(
-                                                    lit.span(),
-                                                    format!("expected a string, int, float or bool, found literal `{}`", lit.into_token_stream()),
-                                                ))
-                                            }
-                                        }
+                                };
+                                res.host_env_vars.push(name.clone());
+                                source = Some(InjectSource::Env(name));
+                            }
+                            "bytes_from_file" => {
+                                // String as PathBuf
+                                source = match expect_lit(&expr, "literal string")? {
+                                    syn::Lit::Str(file) => {
+                                        Some(InjectSource::File(PathBuf::from(file.value())))
                                     }
                                     _ => {
                                         return Err(syn::Error::new(
-                                            field.expr.span(),
-                                            format!("expected a string, int, float or bool, found `{}`", field.expr.into_token_stream()),
+                                            expr.span(),
+                                            "expected literal string",
                                         ))
                                     }
                                 };
-
-                                res.env_vars.push((env_name, env_val));
+                            }
+                            _ => {
+                                return Err(syn::Error::new(field.member.span(), "unexpected key"))
                             }
                         }
+                    }
+                    res.options.inject_data = match (segment, source) {
+                        (Some(segment), Some(source)) => Some(InjectData { segment, source }),
+                        _ => return Err(syn::Error::new(
+                            value.expr.span(),
+                            "expected a `segment` and one of `bytes_from_env` or `bytes_from_file`",
+                        )),
+                    };
+                }
+                "post_process" => {
+                    // String as PathBuf
+                    res.options.post_process = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(tool) => Some(PathBuf::from(tool.value())),
                         _ => {
                             return Err(syn::Error::new(
                                 value.expr.span(),
-                                "expected key value pairs",
+                                "expected literal string",
                             ))
                         }
-                    }
+                    };
                 }
-                option => {
-                    return Err(syn::Error::new(
-                        value.member.span(),
-                        format!("unknown option `{}`", option),
-                    ))
+                "metadata_section" => {
+                    // Boolean
+                    res.options.metadata_section = parse_bool(&value.expr)?;
                 }
-            }
-        }
-
-        Ok(res)
-    }
-}
-
-impl Display for TargetFeatures {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.atomics {
-            write!(f, "+atomics,")?
-        }
-        if self.bulk_memory {
-            write!(f, "+bulk-memory,")?
-        }
-        if self.mutable_globals {
-            write!(f, "+mutable-globals,")?
-        }
-
-        Ok(())
-    }
-}
-
-/// Only allow one build job at a time, in case we are building one module many times.
-static GLOBAL_LOCK: Mutex<()> = Mutex::new(());
-
-/// Builds a cargo project as a webassembly module, returning the bytes of the module produced.
-fn do_build_wasm(args: &Args) -> Result<PathBuf, String> {
-    let Args {
-        module_dir,
-        features,
-        env_vars,
-        release,
-    } = args;
-
-    // Acquire global lock
-    let mut lock = GLOBAL_LOCK.lock();
-    while lock.is_err() {
-        GLOBAL_LOCK.clear_poison();
-        lock = GLOBAL_LOCK.lock();
-    }
-
-    // Check target path points to a module
-    let cargo_config = module_dir.join("Cargo.toml");
-    if !cargo_config.is_file() {
-        return Err(format!(
-            "target directory `{}` does not contain a `Cargo.toml` file",
-            module_dir.display()
-        ));
-    }
-    match std::fs::read_to_string(cargo_config) {
-        Ok(cfg) => {
-            if cfg.contains("[workspace]\n") {
-                return Err("provided directory points to a workspace, not a module".to_owned());
-            }
-        }
-        Err(e) => return Err(format!("failed to read target `Cargo.toml`: {e}")),
-    }
-
-    // Build output path, taking env vars into account
-    let mut target_dir = "target/".to_owned();
-    for (key, val) in env_vars.iter() {
-        target_dir += &format!("{}_{}", key, val);
-    }
-
-    // Run `cargo update` before building
-    let mut command = Command::new("cargo");
-
-    let out = command
-        .arg("update")
-        .current_dir(module_dir.clone())
-        .output();
-    match out {
-        Ok(out) => {
-            if !out.status.success() {
-                return Err(format!(
-                    "failed to update module `{}`: \n{}",
-                    module_dir.display(),
-                    String::from_utf8_lossy(&out.stderr).replace('\n', "\n\t")
-                ));
-            }
-        }
-        Err(e) => {
-            return Err(format!(
-                "failed to update module `{}`: {e}",
-                module_dir.display()
-            ))
-        }
-    }
-
-    // Construct build command
-    let mut command = Command::new("cargo");
-
-    // Treat `RUSTFLAGS` as special in env vars
-    const RUSTFLAGS: &str = "RUSTFLAGS";
-    let mut rustflags_value = format!("--cfg=web_sys_unstable_apis -C target-feature={features}");
-    command.env(RUSTFLAGS, &rustflags_value);
-
-    for (key, val) in env_vars.iter() {
-        if key == RUSTFLAGS {
-            rustflags_value += " ";
-            rustflags_value += val;
-            command.env(RUSTFLAGS, &rustflags_value);
-        } else {
-            command.env(key, val);
-        }
-    }
-
-    // Set args
-    let mut args = vec![
-        "+nightly",
-        "build",
-        "--target",
-        "wasm32-unknown-unknown",
-        "-Z",
-        "build-std=panic_abort,std",
-        "--target-dir",
-        &target_dir,
-    ];
-    if *release {
-        args.push("--release");
-    }
-
-    let command = command.args(args).current_dir(module_dir.clone());
-    let command_debug = format!("{command:?}");
-    let out = command.output();
-    match out {
-        Ok(out) => {
-            if !out.status.success() {
-                return Err(format!(
-                    "failed to build module `{}`: \nrunning `{}`\n{}",
-                    module_dir.display(),
-                    command_debug,
-                    String::from_utf8_lossy(&out.stderr).replace('\n', "\n\t")
-                ));
-            }
-        }
-        Err(e) => {
-            return Err(format!(
-                "failed to build module `{}`: \nrunning `{}`\n{e}",
-                command_debug,
-                module_dir.display()
-            ))
-        }
-    }
-
-    // Find output with glob
-    let root_output = module_dir.join(target_dir).join("wasm32-unknown-unknown/");
-    let glob = if *release {
-        root_output.join("release/")
-    } else {
-        root_output.join("debug/")
-    }
-    .join("*.wasm");
-    let mut glob_paths = glob::glob(
-        glob.as_os_str()
-            .to_str()
-            .expect("output path should be unicode compliant"),
-    )
-    .expect("glob should be valid");
-
-    let output = match glob_paths.next() {
-        Some(Ok(output)) => output,
-        Some(Err(err)) => {
-            return Err(format!(
-                "failed to find output file matching `{glob:?}`: {err} - this is probably a bug",
-            ))
-        }
-        None => {
-            return Err(format!(
-                "failed to find output file matching `{}` - this is probably a bug",
-                glob.display()
-            ))
-        }
+                "dry_run" => {
+                    // Boolean
+                    res.dry_run = parse_bool(&value.expr)?;
+                }
+                "strict_features" => {
+                    // Boolean
+                    res.options.strict_features = parse_bool(&value.expr)?;
+                }
+                "skip_feature_checks" => {
+                    // Boolean
+                    res.options.skip_feature_checks = parse_bool(&value.expr)?;
+                }
+                "skip_manifest_checks" => {
+                    // Boolean
+                    res.options.skip_manifest_checks = parse_bool(&value.expr)?;
+                }
+                "auto_features" => {
+                    // Boolean
+                    res.options.auto_features = parse_bool(&value.expr)?;
+                }
+                "bindgen" => {
+                    // Boolean
+                    res.bindgen = parse_bool(&value.expr)?;
+                }
+                "cache" => {
+                    // Boolean
+                    res.options.cache = parse_bool(&value.expr)?;
+                }
+                "track_ignore" => {
+                    // Array of glob patterns
+                    res.options.track_ignore = expect_str_array(&value.expr)?
+                        .into_iter()
+                        .map(|pattern| match glob::Pattern::new(&pattern.value()) {
+                            Ok(_) => Ok(pattern.value()),
+                            Err(e) => Err(syn::Error::new(
+                                pattern.span(),
+                                format!("invalid glob pattern: {e}"),
+                            )),
+                        })
+                        .collect::<syn::Result<_>>()?;
+                }
+                "normalize_line_endings" => {
+                    // Boolean
+                    res.options.normalize_line_endings = parse_bool(&value.expr)?;
+                }
+                "emit_imports" => {
+                    // Boolean
+                    res.emit_imports = parse_bool(&value.expr)?;
+                }
+                "skip_in_debug" => {
+                    // Boolean
+                    res.skip_in_debug = parse_bool(&value.expr)?;
+                }
+                "emit_memory_info" => {
+                    // Boolean
+                    res.emit_memory_info = parse_bool(&value.expr)?;
+                }
+                "expect_sha256" => {
+                    // String of 64 hex digits
+                    res.expect_sha256 = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(digest)
+                            if digest.value().len() == 64
+                                && digest.value().chars().all(|c| c.is_ascii_hexdigit()) =>
+                        {
+                            Some(digest.value())
+                        }
+                        syn::Lit::Str(digest) => {
+                            return Err(syn::Error::new(
+                                digest.span(),
+                                "expected a SHA-256 digest of 64 hex digits",
+                            ))
+                        }
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "embed" => {
+                    // Identifier naming how the module's bytes are embedded
+                    res.embed_inline = match degroup_expr(value.expr.clone()) {
+                        syn::Expr::Path(path) if path.path.is_ident("include_bytes") => false,
+                        syn::Expr::Path(path) if path.path.is_ident("inline") => true,
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected `include_bytes` or `inline`",
+                            ))
+                        }
+                    };
+                }
+                "sized" => {
+                    // Boolean
+                    res.sized = parse_bool(&value.expr)?;
+                }
+                "report_size" => {
+                    // Boolean
+                    res.report_size = parse_bool(&value.expr)?;
+                }
+                "emit_len" => {
+                    // Boolean
+                    res.emit_len = parse_bool(&value.expr)?;
+                }
+                "emit_world" => {
+                    // Boolean
+                    res.emit_world = parse_bool(&value.expr)?;
+                }
+                "jobs" => {
+                    // Positive integer
+                    res.options.jobs = match expect_lit(&value.expr, "integer")? {
+                        syn::Lit::Int(jobs) => match jobs.base10_parse::<u32>() {
+                            Ok(jobs) if jobs > 0 => Some(jobs),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    jobs.span(),
+                                    "expected a positive number of jobs",
+                                ))
+                            }
+                        },
+                        _ => return Err(syn::Error::new(value.expr.span(), "expected integer")),
+                    };
+                }
+                "quiet" => {
+                    // Boolean
+                    res.options.quiet = parse_bool(&value.expr)?;
+                }
+                "merge_config_rustflags" => {
+                    // Boolean
+                    res.options.merge_config_rustflags = parse_bool(&value.expr)?;
+                }
+                "incremental" => {
+                    // Boolean
+                    res.options.incremental = parse_bool(&value.expr)?;
+                }
+                "reproducible" => {
+                    // Boolean
+                    res.options.reproducible = parse_bool(&value.expr)?;
+                }
+                "component" => {
+                    // Boolean
+                    res.options.component = parse_bool(&value.expr)?;
+                }
+                "command_template" => {
+                    // String as PathBuf
+                    res.options.command_template = match expect_lit(&value.expr, "literal string")?
+                    {
+                        syn::Lit::Str(script) => Some(PathBuf::from(script.value())),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "adapter" => {
+                    // String as PathBuf
+                    res.options.adapter = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(adapter) => Some(PathBuf::from(adapter.value())),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "emit_file" => {
+                    // Boolean to use `OUT_DIR`, or a string as PathBuf
+                    res.emit_file = match expect_lit(&value.expr, "boolean or string")? {
+                        syn::Lit::Bool(emit_file) if emit_file.value => Some(EmitFile::OutDir),
+                        syn::Lit::Bool(_) => None,
+                        syn::Lit::Str(dir) => Some(EmitFile::Dir(PathBuf::from(dir.value()))),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected boolean or string",
+                            ))
+                        }
+                    };
+                }
+                "toolchain" => {
+                    // String naming a rustup toolchain
+                    res.options.toolchain = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(toolchain)
+                            if !toolchain.value().is_empty()
+                                && !toolchain.value().starts_with('+')
+                                && !toolchain.value().contains(char::is_whitespace) =>
+                        {
+                            Some(toolchain.value())
+                        }
+                        syn::Lit::Str(toolchain) => {
+                            return Err(syn::Error::new(
+                                toolchain.span(),
+                                "expected a toolchain name, such as `\"nightly-2024-01-15\"`",
+                            ))
+                        }
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "target" => {
+                    // String naming a WebAssembly target, or the path of a custom target spec file
+                    res.options.target = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(target)
+                            if target.value().starts_with("wasm32-")
+                                || target.value().starts_with("wasm64-")
+                                || target.value().ends_with(".json") =>
+                        {
+                            Some(target.value())
+                        }
+                        syn::Lit::Str(target) => return Err(syn::Error::new(
+                            target.span(),
+                            "expected a WebAssembly target, such as `\"wasm64-unknown-unknown\"`, or the path of a `.json` target spec",
+                        )),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "target_dir" => {
+                    // String as PathBuf
+                    res.options.target_dir = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(dir) => Some(PathBuf::from(dir.value())),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "output_glob" => {
+                    // String giving a glob pattern for the output file name
+                    res.options.output_glob = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(glob) if glob.value().ends_with(".wasm") => {
+                            Some(glob.value())
+                        }
+                        syn::Lit::Str(glob) => {
+                            return Err(syn::Error::new(
+                                glob.span(),
+                                "expected a pattern ending in `.wasm`",
+                            ))
+                        }
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "clean_stale" => {
+                    // Boolean
+                    res.options.clean_stale = parse_bool(&value.expr)?;
+                }
+                "share_std" => {
+                    // Boolean
+                    res.options.share_std = parse_bool(&value.expr)?;
+                }
+                "clean" => {
+                    // Boolean
+                    res.options.clean = parse_bool(&value.expr)?;
+                }
+                "allow_multiple" => {
+                    // Boolean
+                    res.options.allow_multiple = parse_bool(&value.expr)?;
+                }
+                "opt_level" => {
+                    // Integer from 0 to 3, or a string for the size optimisation levels
+                    res.options.opt_level = match expect_lit(&value.expr, "integer or string")? {
+                        syn::Lit::Int(level) => match level.base10_parse::<u8>() {
+                            Ok(level @ 0..=3) => Some(level.to_string()),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    level.span(),
+                                    "expected an optimisation level from 0 to 3",
+                                ))
+                            }
+                        },
+                        syn::Lit::Str(level) => match level.value().as_str() {
+                            level @ ("0" | "1" | "2" | "3" | "s" | "z") => Some(level.to_owned()),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    level.span(),
+                                    "expected one of the optimisation levels `0`, `1`, `2`, `3`, `s` or `z`",
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected integer or string",
+                            ))
+                        }
+                    };
+                }
+                "lto" => {
+                    // Boolean, or a string naming the kind of LTO
+                    res.options.lto = match expect_lit(&value.expr, "boolean or string")? {
+                        syn::Lit::Bool(lto) if lto.value => Some("fat".to_owned()),
+                        syn::Lit::Bool(_) => Some("off".to_owned()),
+                        syn::Lit::Str(lto) => match lto.value().as_str() {
+                            lto @ ("fat" | "thin" | "off") => Some(lto.to_owned()),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    lto.span(),
+                                    "expected one of `\"fat\"`, `\"thin\"` or `\"off\"`",
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected boolean or string",
+                            ))
+                        }
+                    };
+                }
+                "codegen_units" => {
+                    // Positive integer
+                    res.options.codegen_units = match expect_lit(&value.expr, "integer")? {
+                        syn::Lit::Int(units) => match units.base10_parse::<u32>() {
+                            Ok(units) if units > 0 => Some(units),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    units.span(),
+                                    "expected a positive number of codegen units",
+                                ))
+                            }
+                        },
+                        _ => return Err(syn::Error::new(value.expr.span(), "expected integer")),
+                    };
+                }
+                "size_optimized" => {
+                    // Boolean
+                    res.options.size_optimized = parse_bool(&value.expr)?;
+                }
+                "raw_target_features" => {
+                    // String, passed through unvalidated
+                    res.options.raw_target_features =
+                        match expect_lit(&value.expr, "literal string")? {
+                            syn::Lit::Str(raw) => raw.value().trim_matches(',').to_owned(),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    value.expr.span(),
+                                    "expected literal string",
+                                ))
+                            }
+                        };
+                }
+                "panic" => {
+                    // String naming the panic strategy
+                    res.options.panic = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(panic) => match panic.value().as_str() {
+                            panic @ ("abort" | "unwind") => Some(panic.to_owned()),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    panic.span(),
+                                    "expected one of `\"abort\"` or `\"unwind\"`",
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "build_std" => {
+                    // Array of crate names
+                    res.options.build_std = Some(
+                        expect_str_array(&value.expr)?
+                            .into_iter()
+                            .map(|krate| krate.value())
+                            .collect(),
+                    );
+                }
+                "build_std_features" => {
+                    // Array of feature names
+                    res.options.build_std_features = expect_str_array(&value.expr)?
+                        .into_iter()
+                        .map(|feature| feature.value())
+                        .collect();
+                }
+                "z_flags" => {
+                    // Array of strings, each passed to rustc as a `-Z` flag
+                    res.options.z_flags = expect_str_array(&value.expr)?
+                        .into_iter()
+                        .map(|flag| {
+                            if flag.value().is_empty() {
+                                Err(syn::Error::new(flag.span(), "expected a non-empty flag"))
+                            } else if flag.value().contains(char::is_whitespace) {
+                                // `RUSTFLAGS` is split on whitespace
+                                Err(syn::Error::new(
+                                    flag.span(),
+                                    "`-Z` flags may not contain whitespace",
+                                ))
+                            } else {
+                                Ok(flag.value())
+                            }
+                        })
+                        .collect::<syn::Result<_>>()?;
+                }
+                "config" => {
+                    // Array of `key=value` strings, each passed to cargo as a `--config` override
+                    res.options.config = expect_str_array(&value.expr)?
+                        .into_iter()
+                        .map(|config| match config.value().split_once('=') {
+                            Some((key, value))
+                                if !key.trim().is_empty() && !value.trim().is_empty() =>
+                            {
+                                Ok(config.value())
+                            }
+                            _ => Err(syn::Error::new(
+                                config.span(),
+                                "expected a `key=value` config override",
+                            )),
+                        })
+                        .collect::<syn::Result<_>>()?;
+                }
+                "bin" => {
+                    // Non-empty string
+                    res.options.bin = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(bin) if bin.value().is_empty() => {
+                            return Err(syn::Error::new(bin.span(), "expected a non-empty name"))
+                        }
+                        syn::Lit::Str(bin) => Some(bin.value()),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "linker" => {
+                    // String as PathBuf
+                    res.options.linker = match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(linker) => Some(PathBuf::from(linker.value())),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "link_args" => {
+                    // Array of strings, each passed as a `-C link-arg`
+                    res.options.link_args = expect_str_array(&value.expr)?
+                        .into_iter()
+                        .map(|arg| {
+                            // `RUSTFLAGS` is split on whitespace
+                            if arg.value().contains(char::is_whitespace) {
+                                Err(syn::Error::new(
+                                    arg.span(),
+                                    "link arguments may not contain whitespace",
+                                ))
+                            } else {
+                                Ok(arg.value())
+                            }
+                        })
+                        .collect::<syn::Result<_>>()?;
+                }
+                "deny_warnings" => {
+                    // Boolean
+                    res.options.deny_warnings = parse_bool(&value.expr)?;
+                }
+                "web_sys_unstable" => {
+                    // Boolean
+                    res.options.web_sys_unstable = parse_bool(&value.expr)?;
+                }
+                "shared_memory_max" => {
+                    // Integer number of bytes, in whole pages
+                    res.options.shared_memory_max = match expect_lit(&value.expr, "integer")? {
+                        syn::Lit::Int(max) => match max.base10_parse::<u64>() {
+                            Ok(max) if max % WASM_PAGE_SIZE == 0 => Some(max),
+                            _ => {
+                                return Err(syn::Error::new(
+                                    max.span(),
+                                    "expected a multiple of the 65536 byte page size",
+                                ))
+                            }
+                        },
+                        _ => return Err(syn::Error::new(value.expr.span(), "expected integer")),
+                    };
+                }
+                "features" => {
+                    // Array of identifiers, merged with any given by earlier `features` keys
+                    match value.expr {
+                        syn::Expr::Array(syn::ExprArray {
+                            attrs,
+                            bracket_token: _,
+                            elems,
+                        }) if attrs.is_empty() => res
+                            .options
+                            .features
+                            .merge(features_from_list_of_exprs(elems)?),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected array of features",
+                            ))
+                        }
+                    };
+                }
+                "preset" => {
+                    // String naming a set of features, added to any given explicitly
+                    match expect_lit(&value.expr, "literal string")? {
+                        syn::Lit::Str(preset) => match TargetFeatures::preset(&preset.value()) {
+                            Some(features) => res.options.features.merge(features),
+                            None => {
+                                return Err(syn::Error::new(
+                                    preset.span(),
+                                    "unknown preset, expected `\"web\"` or `\"web-2024\"`",
+                                ))
+                            }
+                        },
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected literal string",
+                            ))
+                        }
+                    };
+                }
+                "env" => {
+                    // Dictionary of key value pairs
+                    match value.expr {
+                        syn::Expr::Struct(syn::ExprStruct {
+                            attrs,
+                            qself: None,
+                            path:
+                                syn::Path {
+                                    leading_colon: None,
+                                    segments,
+                                },
+                            brace_token: _,
+                            fields,
+                            dot2_token: None,
+                            rest: None,
+                        }) if attrs.is_empty()
+                            && segments.len() == 1
+                            && segments[0].arguments.is_empty()
+                            && segments[0].ident == "Env" =>
+                        {
+                            for field in fields {
+                                let span = field.span();
+                                if !field.attrs.is_empty() || field.colon_token.is_none() {
+                                    return Err(syn::Error::new(span, "expected key value pair"));
+                                }
+
+                                let env_name = match &field.member {
+                                    syn::Member::Named(name) => name.to_string(),
+                                    _ => {
+                                        return Err(syn::Error::new(
+                                            span,
+                                            "expected env variable name",
+                                        ))
+                                    }
+                                };
+
+                                let mut expr = &field.expr;
+                                while let syn::Expr::Group(syn::ExprGroup {
+                                    attrs,
+                                    group_token: _,
+                                    expr: inner_expr,
+                                }) = expr
+                                {
+                                    if !attrs.is_empty() {
+                                        return Err(syn::Error::new(
+                                            attrs[0].span(),
+                                            "expected a string, int, float or bool",
+                                        ));
+                                    }
+
+                                    expr = inner_expr;
+                                }
+
+                                let env_val = match expr {
+                                    syn::Expr::Lit(syn::ExprLit { attrs, lit })
+                                        if attrs.is_empty() =>
+                                    {
+                                        match lit {
+                                            syn::Lit::Str(v) => v.value(),
+                                            syn::Lit::Int(i) => i.to_string(),
+                                            syn::Lit::Float(f) => f.to_string(),
+                                            syn::Lit::Bool(b) => b.value.to_string(),
+                                            _ => {
+                                                return Err(syn::Error::new(
+                                                    lit.span(),
+                                                    format!("expected a string, int, float or bool, found literal `{}`", lit.into_token_stream()),
+                                                ))
+                                            }
+                                        }
+                                    }
+                                    syn::Expr::Array(_) | syn::Expr::Struct(_) => {
+                                        expr_to_json(expr)?
+                                    }
+                                    syn::Expr::Call(syn::ExprCall {
+                                        attrs,
+                                        func,
+                                        paren_token: _,
+                                        args,
+                                    }) if attrs.is_empty()
+                                        && matches!(&**func, syn::Expr::Path(path) if path.path.is_ident("env")) =>
+                                    {
+                                        // Forwarded from the host environment
+                                        let host_name = match args.iter().collect::<Vec<_>>().as_slice() {
+                                            [arg] => match expect_lit(arg, "literal string")? {
+                                                syn::Lit::Str(host_name) => host_name.value(),
+                                                _ => return Err(syn::Error::new(arg.span(), "expected literal string")),
+                                            },
+                                            _ => return Err(syn::Error::new(expr.span(), "expected the name of a single host environment variable")),
+                                        };
+                                        let host_val = std::env::var(&host_name).map_err(|e| {
+                                            syn::Error::new(
+                                                expr.span(),
+                                                format!("failed to read host environment variable `{host_name}`: {e}"),
+                                            )
+                                        })?;
+                                        res.host_env_vars.push(host_name);
+                                        host_val
+                                    }
+                                    syn::Expr::Call(syn::ExprCall {
+                                        attrs,
+                                        func,
+                                        paren_token: _,
+                                        args,
+                                    }) if attrs.is_empty()
+                                        && matches!(&**func, syn::Expr::Path(path) if path.path.is_ident("file") || path.path.is_ident("file_bytes")) =>
+                                    {
+                                        // Read from a file, relative to the invoking file
+                                        let file = match args.iter().collect::<Vec<_>>().as_slice() {
+                                            [arg] => match expect_lit(arg, "literal string")? {
+                                                syn::Lit::Str(file) => file.clone(),
+                                                _ => return Err(syn::Error::new(arg.span(), "expected literal string")),
+                                            },
+                                            _ => return Err(syn::Error::new(expr.span(), "expected the path of a single file")),
+                                        };
+                                        let bytes = matches!(&**func, syn::Expr::Path(path) if path.path.is_ident("file_bytes"));
+                                        env_files.push((res.options.env_vars.len(), file, bytes));
+                                        String::new()
+                                    }
+                                    _ => {
+                                        return Err(syn::Error::new(
+                                            field.expr.span(),
+                                            format!("expected a string, int, float, bool, array or struct, found `{}`", field.expr.into_token_stream()),
+                                        ))
+                                    }
+                                };
+
+                                res.options.env_vars.push((env_name, env_val));
+                            }
+                        }
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.expr.span(),
+                                "expected key value pairs",
+                            ))
+                        }
+                    }
+                }
+                "config_file" => {
+                    // Read before the other options
+                }
+                "inherit_env" => {
+                    // Array of variable name prefixes
+                    inherit_env_prefixes = expect_str_array(&value.expr)?
+                        .into_iter()
+                        .map(|prefix| prefix.value())
+                        .collect();
+                }
+                option => {
+                    return Err(syn::Error::new(
+                        value.member.span(),
+                        format!("unknown option `{}`", option),
+                    ))
+                }
+            }
+        }
+
+        // Otherwise the build fails later, with a confusing error about a missing `Cargo.toml`
+        if !has_path && res.prebuilt.is_none() {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "missing required `path` option",
+            ));
+        }
+        if res.prebuilt.is_some() {
+            if has_path || res.options.manifest_path.is_some() {
+                return Err(syn::Error::new(
+                    input.span(),
+                    "`prebuilt` cannot be used with `path` or `manifest_path`",
+                ));
+            }
+            if res.dry_run || res.bindgen || res.options.component {
+                return Err(syn::Error::new(
+                    input.span(),
+                    "`prebuilt` cannot be used with `dry_run`, `bindgen` or `component`",
+                ));
+            }
+        }
+        if res.options.manifest_path.is_some()
+            && res.options.module_dir.file_name() == Some("Cargo.toml".as_ref())
+        {
+            return Err(syn::Error::new(
+                input.span(),
+                "`manifest_path` cannot be used when `path` is a `Cargo.toml`",
+            ));
+        }
+        if res.sized && (res.bindgen || res.emit_file.is_some()) {
+            return Err(syn::Error::new(
+                input.span(),
+                "`sized` cannot be used with `emit_file` or `bindgen`",
+            ));
+        }
+        if res.embed_inline && res.emit_file.is_some() {
+            return Err(syn::Error::new(
+                input.span(),
+                "`embed: inline` cannot be used with `emit_file`",
+            ));
+        }
+        if res.bindgen && res.emit_file.is_some() {
+            return Err(syn::Error::new(
+                input.span(),
+                "`bindgen` cannot be used with `emit_file`",
+            ));
+        }
+        if res.bindgen && (res.emit_imports || res.emit_len || res.emit_memory_info) {
+            return Err(syn::Error::new(
+                input.span(),
+                "`bindgen` cannot be used with `emit_imports`, `emit_len` or `emit_memory_info`",
+            ));
+        }
+        if res.options.component && res.emit_memory_info {
+            return Err(syn::Error::new(
+                input.span(),
+                "`component` cannot be used with `emit_memory_info`",
+            ));
+        }
+        if res.options.component && (res.bindgen || res.emit_imports) {
+            return Err(syn::Error::new(
+                input.span(),
+                "`component` cannot be used with `bindgen` or `emit_imports`",
+            ));
+        }
+        if res.options.adapter.is_some() && !res.options.component {
+            return Err(syn::Error::new(
+                input.span(),
+                "`adapter` can only be used with `component`",
+            ));
+        }
+        if res.options.clean && res.options.target_dir.is_some() {
+            return Err(syn::Error::new(
+                input.span(),
+                "`clean` cannot be used with `target_dir`",
+            ));
+        }
+        if res.options.clean && res.options.share_std {
+            return Err(syn::Error::new(
+                input.span(),
+                "`clean` cannot be used with `share_std`",
+            ));
+        }
+        if res.emit_world && !res.options.component {
+            return Err(syn::Error::new(
+                input.span(),
+                "`emit_world` can only be used with `component`",
+            ));
+        }
+
+        if !env_files.is_empty() {
+            let invocation_dir = invocation_dir(
+                res.prebuilt
+                    .as_deref()
+                    .unwrap_or(res.options.module_dir.as_path()),
+            )
+            .map_err(|e| syn::Error::new(input.span(), e))?;
+            for (index, file, bytes) in env_files {
+                let path = resolve_path(&invocation_dir, Path::new(&file.value()));
+                let contents = std::fs::read(&path).map_err(|e| {
+                    syn::Error::new(
+                        file.span(),
+                        format!("failed to read env file `{}`: {e}", path.display()),
+                    )
+                })?;
+                res.options.env_vars[index].1 = if bytes {
+                    base64(&contents)
+                } else {
+                    String::from_utf8(contents).map_err(|_| {
+                        syn::Error::new(
+                            file.span(),
+                            format!(
+                                "env file `{}` isn't UTF-8, use `file_bytes` to pass it as base64",
+                                path.display()
+                            ),
+                        )
+                    })?
+                };
+                res.env_files.push(path);
+            }
+        }
+
+        // These decide how the module is built, so changes to them should trigger a rebuild
+        res.host_env_vars
+            .extend([RUSTC_BOOTSTRAP.to_owned(), CARGO_TARGET_DIR.to_owned()]);
+        if res.skip_in_debug {
+            res.host_env_vars.push(INCLUDE_WASM_RS_SKIP.to_owned());
+        }
+
+        // Inherited variables come first, so that explicit ones take precedence
+        if !inherit_env_prefixes.is_empty() {
+            let mut inherited = std::env::vars_os()
+                .filter_map(|(name, val)| Some((name.into_string().ok()?, val.into_string().ok()?)))
+                .filter(|(name, _)| {
+                    inherit_env_prefixes
+                        .iter()
+                        .any(|prefix| name.starts_with(prefix))
+                })
+                .filter(|(name, _)| {
+                    // `RUSTFLAGS` are merged rather than replaced, so both are kept
+                    name == "RUSTFLAGS"
+                        || !res
+                            .options
+                            .env_vars
+                            .iter()
+                            .any(|(explicit, _)| explicit == name)
+                })
+                .collect::<Vec<_>>();
+            // Keep the target directory stable
+            inherited.sort();
+
+            res.host_env_vars
+                .extend(inherited.iter().map(|(name, _)| name.clone()));
+            res.options.env_vars.splice(0..0, inherited);
+        }
+
+        Ok(res)
+    }
+}
+
+/// Where a built module should be copied to, to be loaded at runtime rather than included in the binary.
+#[derive(Clone)]
+enum EmitFile {
+    /// The `OUT_DIR` of the invoking crate.
+    OutDir,
+    Dir(PathBuf),
+}
+
+/// Copies a built module to where it should be emitted, returning the path of the copy.
+fn copy_to_emit_dir(output: &Path, emit_file: &EmitFile) -> Result<PathBuf, String> {
+    let dir = match emit_file {
+        EmitFile::OutDir => match std::env::var_os("OUT_DIR") {
+            Some(out_dir) => PathBuf::from(out_dir),
+            None => {
+                return Err("`OUT_DIR` is not set, since the invoking crate has no build script - \
+                    give a directory to copy the module to with `emit_file: \"path/to/dir\"` instead"
+                    .to_owned())
+            }
+        },
+        EmitFile::Dir(dir) => dir.clone(),
+    };
+
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| format!("failed to create directory `{}`: {e}", dir.display()))?;
+    let emitted = dir.join(output.file_name().expect("output should be a file"));
+    std::fs::copy(output, &emitted)
+        .map_err(|e| format!("failed to copy module to `{}`: {e}", emitted.display()))?;
+
+    Ok(emitted)
+}
+
+/// Gives a value holding a module processed by `wasm-bindgen`, alongside its JS glue.
+fn bindgen_value(
+    wasm: proc_macro2::TokenStream,
+    js: proc_macro2::TokenStream,
+) -> proc_macro2::TokenStream {
+    quote! {
+        {
+            #[derive(Clone, Copy, Debug)]
+            struct BindgenModule {
+                wasm: &'static [u8],
+                js: &'static str,
+            }
+            BindgenModule {
+                wasm: #wasm,
+                js: #js,
+            }
+        }
+    }
+}
+
+/// Wraps the given expression, registering the files and host environment variables that the module
+/// depends on so that the invoking crate is rebuilt when they change.
+///
+/// Where the compiler can't be told about a dependency directly, a dummy `include_bytes!` or `option_env!`
+/// is emitted instead, making the expression a block. Both are allowed in constant expressions, so the
+/// block can still initialize a `static` or `const`.
+fn tracked_expr(
+    value: proc_macro2::TokenStream,
+    files: &[String],
+    env_vars: &[String],
+) -> proc_macro2::TokenStream {
+    // Only pushed to if some kind of dependency can't be tracked directly
+    #[allow(unused_mut)]
+    let mut tracking: Vec<proc_macro2::TokenStream> = Vec::new();
+
+    for path in files {
+        #[cfg(feature = "tracked_path")]
+        proc_macro::tracked::path(path);
+        #[cfg(not(feature = "tracked_path"))]
+        // Unlike `include_str!`, this accepts files that aren't UTF-8, such as binary assets
+        tracking.push(quote! { let _ = include_bytes!(#path); });
+    }
+    for name in env_vars {
+        #[cfg(feature = "tracked_env")]
+        let _ = proc_macro::tracked::env_var(name);
+        #[cfg(not(feature = "tracked_env"))]
+        tracking.push(quote! { let _ = option_env!(#name); });
+    }
+
+    if tracking.is_empty() {
+        value
+    } else {
+        quote! {
+            {
+                #(#tracking)*
+                #value
+            }
+        }
+    }
+}
+
+/// Encodes bytes as standard base64, with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut res = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (u32::from(*byte) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+/// Shows a note to the user during compilation.
+fn emit_note(message: &str) {
+    #[cfg(feature = "proc_macro_diagnostic")]
+    proc_macro::Span::call_site().note(message).emit();
+    #[cfg(not(feature = "proc_macro_diagnostic"))]
+    eprintln!("note: {message}");
+}
+
+/// Finds the directory containing the source file that the macro was invoked in, given the path
+/// that was passed to the macro.
+fn invocation_dir(path_arg: &Path) -> Result<PathBuf, String> {
+    #[cfg(not(feature = "proc_macro_span"))]
+    let invocation_file = {
+        let root =
+            std::env::var("CARGO_MANIFEST_DIR").expect("proc macros should be run using cargo");
+        find_me(&root, &format!("\"{}\"", path_arg.to_string_lossy()))
+    };
+    #[cfg(feature = "proc_macro_span")]
+    let invocation_file = proc_macro::Span::call_site().source_file().path();
+    let dir = invocation_file.parent().ok_or_else(|| {
+        format!(
+            "invoking file `{}` has no parent directory",
+            invocation_file.display()
+        )
+    })?;
+    // Fails if the file was moved or deleted during the build, or on some unusual filesystems
+    dir.canonicalize().map_err(|e| {
+        format!(
+            "failed to canonicalize `{}`, the directory of the invoking file: {e}",
+            dir.display()
+        )
+    })
+}
+
+/// Invokes `cargo build` at compile time on another module, replacing this macro invocation
+/// with the bytes contained in the output `.wasm` file.
+///
+/// # Usage
+///
+/// ```ignore
+/// let module = build_wasm!("relative/path/to/module");
+/// ```
+///
+/// The macro expands to a constant expression, so it can also initialize a `static` or `const`:
+///
+/// ```ignore
+/// static MODULE: &[u8] = build_wasm!("relative/path/to/module");
+/// ```
+///
+/// # Arguments
+///
+/// This macro can take a number of additional arguments to control how the WebAssembly should be generated.
+/// These options are passed to `cargo build`:
+///
+/// ```ignore
+/// let module = build_wasm!{
+///     // The module's directory, or its `Cargo.toml`, relative to the invoking file unless absolute.
+///     path: "relative/path/to/module",
+///     // Builds the crate with this `Cargo.toml`, while cargo still runs in `path`, so that cargo's config files are found
+///     // from `path` rather than from the crate. Relative to the invoking file unless absolute. To build a member of a
+///     // workspace, give the workspace as `path` and the member's `Cargo.toml` here. Can't be used if `path` is a `Cargo.toml`.
+///     manifest_path: "relative/path/to/module/member/Cargo.toml",
+///     // Reads default options from a TOML file, relative to the invoking file unless absolute, e.g. to share options
+///     // between every module of a project. Each key is an option, with its value as it would be given here, e.g.
+///     // `release = true` or `env = { LOG = "debug" }`. Options given directly replace those in the file. The file can't
+///     // give `path` or `prebuilt`, and changing it rebuilds the module.
+///     config_file: "wasm-build.toml",
+///     // Features may be given as identifiers or strings. If `features` is given more than once, e.g. by a wrapping
+///     // macro that sets defaults for the caller to add to, every feature given is enabled.
+///     features: [
+///         atomics, // Controls if the `atomics` proposal is enabled
+///         bulk_memory, // Controls if the `bulk-memory` proposal is enabled
+///         mutable_globals, // Controls if the `mutable-globals` proposal is enabled
+///         sign_ext, // Controls if the `sign-ext` proposal is enabled
+///         nontrapping_fptoint, // Controls if the `nontrapping-fptoint` proposal is enabled
+///         multivalue, // Controls if the `multivalue` proposal is enabled
+///         reference_types, // Controls if the `reference-types` proposal is enabled
+///         tail_call, // Controls if the `tail-call` proposal is enabled
+///         memory64, // Controls if the `memory64` proposal is enabled. Requires the `wasm64-unknown-unknown` target
+///         all, // Enables every proposal above other than `memory64`, alongside any others listed
+///         !sign_ext, // Disables a proposal, even if enabled by default, by `all`, by a `preset` or by the module itself
+///     ],
+///     // Enables a named set of features, in addition to those given in `features`. Presets are versioned by year,
+///     // and a versioned preset always enables the same features, while an unversioned one refers to the latest.
+///     // "web-2024" (or "web") enables the proposals shipped by default in all major browsers as of 2024:
+///     // `bulk_memory`, `mutable_globals`, `sign_ext`, `nontrapping_fptoint`, `multivalue` and `reference_types`.
+///     preset: "web",
+///     // Also enables the features that the module requests in its `Cargo.toml`, as a `features` list (using either
+///     // form of the names above) and an optional `preset`, both in the `[package.metadata.wasm]` table, e.g.
+///     // `[package.metadata.wasm] features = ["atomics", "bulk-memory"]`.
+///     auto_features: true,
+///     // Skips checking that the enabled features can be used together on the target, before building. The checks are
+///     // conservative, and currently only require `bulk_memory` alongside `atomics`, since shared memories need passive data
+///     // segments, a target with threads for `atomics`, e.g. `wasm32-wasip1-threads` rather than `wasm32-wasip1`, and a
+///     // 64-bit target for `memory64`.
+///     skip_feature_checks: true,
+///     // Checks that each target feature, including those in `raw_target_features` and `RUSTFLAGS`, is known to the
+///     // toolchain for the target, by running `rustc --print target-features` once for each toolchain and target. rustc
+///     // otherwise only warns about unknown features, so a typo silently does nothing. The check is skipped if rustc's
+///     // output can't be understood. Defaults to false.
+///     strict_features: true,
+///     // Skips the checks made on the module's `Cargo.toml` before building, which turn known footguns into clear
+///     // errors: that it has a library or binary target at all, that it has a `cdylib` or `bin` target, and that it
+///     // doesn't use an edition before 2021 (including by giving no `edition`) without choosing a `resolver`, since
+///     // the old resolver breaks `build_std` in confusing ways.
+///     skip_manifest_checks: true,
+///     // Appended verbatim to the `-C target-feature` list, for proposals without a named feature above.
+///     // These are not validated against the toolchain.
+///     raw_target_features: "+simd128,+relaxed-simd",
+///     // Allows additional environment variables to be set while compiling the module.
+///     // `RUSTFLAGS` is appended to the flags generated from the other options, and any `-C target-feature`
+///     // flags it contains are merged into the end of the generated feature list, so take precedence.
+///     // `CARGO_ENCODED_RUSTFLAGS` is merged in the same way, and is never inherited from the host environment.
+///     env: Env {
+///         FOO: "bar",
+///         BAX: 7,
+///         // Arrays and struct literals are serialized to compact JSON, with struct names ignored.
+///         // This sets `CONFIG={"width":32,"names":["a","b"]}`.
+///         CONFIG: Config { width: 32, names: ["a", "b"] },
+///         // Forwards the value of `HOST_VAR` from the environment that the macro is expanded in.
+///         MY_VAR: env("HOST_VAR"),
+///         // Reads the value from a file, relative to the invoking file, which must be UTF-8. Changes to the file rebuild
+///         // the module.
+///         SCHEMA: file("schema.json"),
+///         // Reads the value from a binary file, passed as standard base64.
+///         KEY: file_bytes("key.bin"),
+///     },
+///     // Forwards every variable in the host environment whose name starts with one of these prefixes.
+///     // Variables given explicitly in `env` take precedence, other than `RUSTFLAGS`, which are merged.
+///     inherit_env: ["CARGO_", "MY_APP_"],
+///     // The toolchain to build the module with, e.g. to pin a nightly that is known to work. Defaults to "nightly".
+///     // Takes precedence over both `rust-toolchain.toml` files and `RUSTC_BOOTSTRAP`.
+///     toolchain: "nightly-2024-01-15",
+///     // The target to build for. Defaults to "wasm32-unknown-unknown". "wasm64-unknown-unknown" builds a module using the
+///     // `memory64` proposal, for modules needing more than 4GiB of memory, and requires `build_std` (as by default), since
+///     // there is no prebuilt standard library for it. A path ending in `.json`, relative to the invoking file, names a
+///     // custom target spec file, which is passed to cargo and tracked for rebuilds. Custom targets also require `build_std`,
+///     // and `memory64` isn't checked against them.
+///     target: "wasm64-unknown-unknown",
+///     // Controls if the module should be built in debug or release mode.
+///     release: true,
+///     // If more than one module is found in the output directory, e.g. after renaming the module, delete the
+///     // output directory and rebuild once before giving up. Only ever deletes within the module's target directory.
+///     clean_stale: true,
+///     // Deletes the module's target directory before building, forcing a build from scratch, for chasing stale-artifact
+///     // bugs or checking that builds are reproducible. This slows down every build, so is only meant for diagnostics.
+///     // Only the directory chosen by this crate for this module is deleted, so this can't be used with `target_dir`
+///     // or `share_std`.
+///     clean: true,
+///     // If more than one module is found in the output directory, e.g. when the module intentionally produces several,
+///     // use the first by sorted filename rather than failing. The file that cargo reports building is still preferred,
+///     // unless `output_glob` is given. Takes precedence over `clean_stale`.
+///     allow_multiple: true,
+///     // The pattern used to find the built module in the output directory, for when cargo can't say which file it is,
+///     // e.g. when the artifact is named differently to the crate. Must end in `.wasm`. Defaults to `*.wasm`, which never
+///     // finds files in cargo's intermediate directories (`deps`, `incremental`, `examples` and `build`), while a given
+///     // pattern may.
+///     output_glob: "my_*.wasm",
+///     // Builds only the binary target with this name, passing `--bin` to cargo, and includes its `.wasm` file. This is for
+///     // modules written as a binary with a `main` function, as an alternative to a `cdylib` exporting `#[no_mangle]`
+///     // functions. Binaries are built to the same directory as libraries, so this also changes the default `output_glob`.
+///     bin: "my_module",
+///     // By default, each configuration of a module is built in its own directory under the module's `target`
+///     // directory (or under `$CARGO_TARGET_DIR/wasm-guests` if `CARGO_TARGET_DIR` is set when building the host),
+///     // so that switching between configurations doesn't cause rebuilds, at the cost of disk space.
+///     // This gives a single directory to use instead, relative to the invoking file, and takes precedence over
+///     // `CARGO_TARGET_DIR`.
+///     target_dir: "relative/path/to/target",
+///     // Builds the module in a directory shared by every module built with the same configuration, named
+///     // `wasm-std-{toolchain}-{hash}` under `$CARGO_TARGET_DIR` or the invoking crate's `target` directory, so that the
+///     // standard library built by `build_std`, which is most of the time taken by a cold build, is only built once
+///     // rather than once per module. `env` isn't part of the configuration, so changing it rebuilds the crates that read
+///     // it in place. `target_dir` takes precedence.
+///     share_std: true,
+///     // A tool to run on a copy of the built module before it is included, given the copy's path as its argument.
+///     // The tool may either rewrite the file in place, or print the new module to stdout.
+///     post_process: "./scripts/sign.sh",
+///     // Overwrites the start of a data segment of the module, given by its index, with the contents of a host environment
+///     // variable (`bytes_from_env`) or of a file relative to the invoking file (`bytes_from_file`), e.g. to bake in a
+///     // configuration blob without recompiling the module. The rest of the segment is left as it was, so the bytes must
+///     // fit in the segment. This is done after `metadata_section`, and before any other post-processing. Segments can't be
+///     // chosen by name, since their names are only kept in the optional `name` custom section.
+///     inject_data: { segment: 0, bytes_from_env: "CONFIG" },
+///     // Removes the `producers`, `name` and DWARF `.debug*` custom sections from the module, which hold toolchain
+///     // versions, symbols and paths, so that the same source and toolchain give the same bytes on any machine.
+///     // This is done before any other post-processing. Note that `metadata_section` includes a timestamp.
+///     reproducible: true,
+///     // Sets `CARGO_INCREMENTAL=0` when building the module, as incremental compilation can make the output differ
+///     // between builds of the same source. This slows down rebuilds, but complements `reproducible`. Defaults to true,
+///     // keeping cargo's default of compiling debug builds incrementally.
+///     incremental: false,
+///     // Fails to compile unless the SHA-256 digest of the included bytes is this, given in hex, to pin a module and
+///     // catch changes to it, e.g. from toolchain drift. The error gives the actual digest, to update it intentionally.
+///     // This is most useful alongside `reproducible`. Not checked in dry runs, or when skipped by `skip_in_debug`.
+///     expect_sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+///     // Adds a custom section named `include-wasm-rs` to the module, containing a JSON object with the
+///     // target `features`, `profile`, `toolchain`, git `commit` and unix `timestamp` of the build.
+///     // This is added before any post-processing. The section's name is also available as
+///     // `include_wasm_rs_build::METADATA_SECTION_NAME`, for reading the section back.
+///     metadata_section: true,
+///     // Instead of building, shows the cargo command that would be run as a note, and gives an empty module.
+///     dry_run: true,
+///     // Gives an empty module rather than building this one when `INCLUDE_WASM_RS_SKIP` is set in the environment that
+///     // the host is built in, to speed up iterating on the host. Proc macros can't see the host's profile, so this is
+///     // up to you, but including a skipped module in a build without `debug_assertions` fails to compile.
+///     skip_in_debug: true,
+///     // Passed to cargo as `--jobs`, to limit how many crates of the module are built at once, e.g. so that the module's
+///     // build doesn't compete with the host's for CPUs. Must be positive. Defaults to cargo's default, the number of CPUs.
+///     jobs: 2,
+///     // Passes `--quiet` to cargo and disables its progress bars, to keep CI logs clean. Errors are still shown.
+///     quiet: true,
+///     // Shows a note during compilation with the size in bytes of the included module, and its size before and after
+///     // `wasm-bindgen` if `bindgen` is used. With `INCLUDE_WASM_RS_LOG=debug`, the sizes before and after any processing of
+///     // the built module (e.g. `post_process`) are also logged. Skipped by dry runs. Defaults to false.
+///     report_size: true,
+///     // Records a fingerprint of the build's inputs in the target directory, and skips running cargo entirely
+///     // if they haven't changed since the last build. This also skips `cargo update`. The inputs include the version
+///     // given by `rustc -vV`, so updating the toolchain rebuilds the module. Defaults to true.
+///     cache: false,
+///     // When deciding if the module has changed since its last build for `cache`, treats CRLF and LF line endings
+///     // in its text files as the same, so that checkouts on different platforms agree. Files containing a NUL byte
+///     // are compared exactly. Disable this if the module depends on the exact bytes of its files. Defaults to true.
+///     normalize_line_endings: false,
+///     // Glob patterns, relative to the module's directory, of files that changes to shouldn't rebuild the module, e.g.
+///     // generated sources. Files in `target` and `.git` directories are never tracked.
+///     track_ignore: ["src/generated/**"],
+///     // Instead of including the module's bytes, copies the module to the invoking crate's `OUT_DIR` (or a given
+///     // directory, relative to the invoking file) and gives the path of the copy as a `&'static str`, to be read at
+///     // runtime. This keeps large modules out of the binary, but the file must then be present when running.
+///     emit_file: true,
+///     // Expands to `include_bytes!` alone, typed `&'static [u8; N]`, rather than to a block cast to `&'static [u8]`, so
+///     // that e.g. `static MODULE: &[u8; N] = build_wasm!{ ... }` compiles. Without the `tracked_path` feature the expansion
+///     // is still a block, since the module's files are tracked by statements within it. Dry runs still give
+///     // `&'static [u8]`. Can't be used with `emit_file` or `bindgen`.
+///     sized: true,
+///     // How the module's bytes are embedded. `include_bytes` (the default) leaves the compiler to read the built file later,
+///     // so it must still exist then. `inline` reads the file while expanding the macro and embeds its bytes (and the JS glue
+///     // of `bindgen`) as literals, for packaging flows that clean the module's target directory before the host is fully
+///     // compiled. Large modules then make for large token streams, slowing compilation and using more of its memory.
+///     // Can't be used with `emit_file`.
+///     embed: inline,
+///     // Runs the `wasm-bindgen` CLI (with `--target web`) on the module, which must be a `cdylib` using `wasm-bindgen`,
+///     // and gives a struct with the processed module as `wasm: &'static [u8]` and its JS glue as `js: &'static str`.
+///     // The CLI must be the same version as the module's `wasm-bindgen` dependency. Can't be used with `emit_file`.
+///     bindgen: true,
+///     // Makes the macro expand to items rather than an expression: `pub const MODULE` holding the module, and
+///     // `pub const IMPORTS: &[(&str, &str)]` listing the `(module, name)` of each import the module requires.
+///     // Can't be used with `bindgen`.
+///     emit_imports: true,
+///     // Makes the macro expand to items rather than an expression, like `emit_imports`: `pub const MODULE` holding
+///     // the module, and `pub const LEN: usize` holding its length in bytes, for use in constant expressions. Can be used
+///     // alongside `emit_imports` or `emit_world`, and can't be used with `bindgen`. Since the names of the items are
+///     // fixed, wrap each invocation in its own `mod` to include many modules in one scope.
+///     emit_len: true,
+///     // Makes the macro expand to items rather than an expression, like `emit_imports`: `pub const MODULE` holding
+///     // the module, and `pub const MEMORY_MIN_PAGES: u32` and `pub const MEMORY_MAX_PAGES: Option<u32>` holding the
+///     // initial and maximum sizes of its memory, in 64KiB pages, for hosts to size their memory ahead of time. If the
+///     // memory is imported, these are the limits of the import. A module without a memory gives 0 and `Some(0)`.
+///     // Can be used alongside the other `emit_` options, and can't be used with `bindgen` or `component`.
+///     emit_memory_info: true,
+///     // Turns the module into a WebAssembly component by running the `wasm-tools` CLI (`wasm-tools component new`)
+///     // on it, and gives the component instead. Can't be used with `bindgen` or `emit_imports`.
+///     component: true,
+///     // The WASI adapter passed to `wasm-tools component new` with `--adapt`, relative to the invoking file, for
+///     // modules built against WASI preview 1. Requires `component`.
+///     adapter: "wasi_snapshot_preview1.reactor.wasm",
+///     // Makes the macro expand to items rather than an expression, like `emit_imports`: `pub const MODULE` holding
+///     // the component, `pub const WORLD: &str` holding its WIT world as printed by `wasm-tools component wit`, and
+///     // `pub const EXPORTS: &[&str]` and `pub const IMPORTS: &[&str]` listing the names of its exported and imported
+///     // interfaces and functions, e.g. `wasi:cli/run@0.2.0`, so that hosts can check their bindings. Requires `component`.
+///     emit_world: true,
+///     // Since the generated `RUSTFLAGS` stop cargo reading rustflags from `.cargo/config.toml` files, reads the
+///     // `target.wasm32-unknown-unknown.rustflags` (or `build.rustflags`) from the module's config files, and
+///     // merges them in before any `RUSTFLAGS` given in `env`.
+///     merge_config_rustflags: true,
+///     // Sets `-C opt-level`, overriding the level given by the profile. One of 0, 1, 2, 3, "s" or "z".
+///     opt_level: "s",
+///     // Sets `-C lto`, overriding the profile. Either a boolean, "fat", "thin" or "off".
+///     lto: true,
+///     // Sets `-C codegen-units`, overriding the profile.
+///     codegen_units: 1,
+///     // Sets `-C panic`. Either "abort" or "unwind".
+///     panic: "abort",
+///     // A preset for the smallest module, defaulting `opt_level` to "z", `lto` to "fat", `codegen_units` to 1 and
+///     // `panic` to "abort", and adding `-C strip=debuginfo`. Any of those options given explicitly take precedence.
+///     // Defaults to false.
+///     size_optimized: true,
+///     // The crates passed to `-Z build-std`. Defaults to the panic crate matching `panic`, and `std`, other than for WASI
+///     // targets without a `panic`, which default to `std` alone. A list given here is used as is, and an empty list
+///     // disables `build-std`.
+///     build_std: ["panic_abort", "std"],
+///     // The features of the standard library passed to `-Z build-std-features`. Requires `build_std` to be non-empty.
+///     // `panic_immediate_abort` removes the panic formatting machinery, which makes modules much smaller, but panics
+///     // then abort without any message, which makes them much harder to diagnose.
+///     build_std_features: ["panic_immediate_abort"],
+///     // Each is passed to rustc as a `-Z` flag. These are unstable, so may change between nightly toolchains.
+///     z_flags: ["wasm-c-abi=spec", "location-detail=none"],
+///     // Each is passed to cargo as a `--config` override, to tune the module's build without editing its manifest.
+///     // Each must be a `key=value` pair, using TOML syntax for the value. Requires cargo 1.63 or newer.
+///     config: ["profile.release.debug=true", "net.offline=true"],
+///     // Sets `-C linker`. A path, rather than a name, is relative to the invoking file.
+///     linker: "rust-lld",
+///     // A script, relative to the invoking file, that rewrites the arguments given to cargo, as described in
+///     // "Command Templates" below. If the script doesn't exist, the arguments are used as planned.
+///     command_template: "./scripts/cargo_args.py",
+///     // Each is passed as a `-C link-arg`.
+///     link_args: ["--import-memory"],
+///     // Adds `-D warnings`, so that the build fails if the module has any warnings.
+///     deny_warnings: true,
+///     // Adds `--cfg=web_sys_unstable_apis`, enabling `web-sys`'s unstable APIs. Defaults to true.
+///     web_sys_unstable: false,
+///     // Modules using the `atomics` feature need a shared memory, so `--shared-memory`, `--import-memory` and
+///     // `--max-memory` link args are added whenever `atomics` is enabled, unless given in `link_args`.
+///     // This sets the size given to `--max-memory`, in bytes. Must be a multiple of 65536, and defaults to 1GiB.
+///     shared_memory_max: 1073741824
+/// };
+/// ```
+///
+/// # Prebuilt Modules
+///
+/// A module that was built ahead of time, e.g. one that is vendored, can be included in place of `path`, without
+/// running cargo. The file is checked to be a WebAssembly module or component, and tracked for rebuilds:
+///
+/// ```ignore
+/// let module = build_wasm!{ prebuilt: "relative/path/to/module.wasm" };
+/// ```
+///
+/// The options that decide how a module is built are ignored, and `dry_run`, `bindgen` and `component` can't be used.
+#[proc_macro]
+pub fn build_wasm(args: TokenStream) -> TokenStream {
+    // Parse args
+    let args = parse_macro_input!(args as Args);
+
+    expand_build_wasm(args, false).into()
+}
+
+/// Builds a Rust WebAssembly module at compile time like [`build_wasm!`], taking the same arguments, but
+/// gives a `Result` rather than failing to compile if the module can't be built.
+///
+/// On success this gives `Ok` of what `build_wasm!` would give, e.g. `Ok(&'static [u8])`, and on failure
+/// gives `Err(&'static str)` holding the build error, allowing optional modules to be left out at runtime.
+/// Can't be used with `emit_imports`, `emit_world`, `emit_len` or `emit_memory_info`.
+///
+/// # Usage
+///
+/// ```ignore
+/// let module: Result<&'static [u8], &'static str> = build_wasm_optional!("relative/path/to/module");
+/// ```
+#[proc_macro]
+pub fn build_wasm_optional(args: TokenStream) -> TokenStream {
+    // Parse args
+    let args = parse_macro_input!(args as Args);
+    if args.emits_items() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "`emit_imports`, `emit_world`, `emit_len` and `emit_memory_info` cannot be used with `build_wasm_optional`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    expand_build_wasm(args, true).into()
+}
+
+/// Builds a module as described by the given arguments, giving the expansion of `build_wasm!`, or of
+/// `build_wasm_optional!` if `optional`.
+fn expand_build_wasm(mut args: Args, optional: bool) -> proc_macro2::TokenStream {
+    if let Err(err) = resolve_paths(&mut args) {
+        return syn::Error::new(proc_macro2::Span::call_site(), err).to_compile_error();
+    }
+    match args.prebuilt.clone() {
+        Some(prebuilt) => expand_built_wasm(args, optional, |_| check_module_file(&prebuilt)),
+        None => expand_built_wasm(args, optional, include_wasm_rs_build::build),
+    }
+}
+
+/// Makes a path given in the arguments relative to the invoking file, unless it is absolute, in which case
+/// it is used as given.
+fn resolve_path(invocation_dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_owned()
+    } else {
+        invocation_dir.join(path)
+    }
+}
+
+/// Makes the paths given in the arguments relative to the invoking file.
+fn resolve_paths(args: &mut Args) -> Result<(), String> {
+    let invocation_dir = invocation_dir(
+        args.prebuilt
+            .as_deref()
+            .unwrap_or(args.options.module_dir.as_path()),
+    )?;
+    if let Some(prebuilt) = &mut args.prebuilt {
+        *prebuilt = resolve_path(&invocation_dir, prebuilt);
+    }
+    args.options.module_dir = resolve_path(&invocation_dir, &args.options.module_dir);
+    if let Some(manifest_path) = &mut args.options.manifest_path {
+        *manifest_path = resolve_path(&invocation_dir, manifest_path);
+    }
+    // A path to the manifest itself builds the module containing it
+    if args.options.module_dir.file_name() == Some("Cargo.toml".as_ref())
+        && args.options.module_dir.is_file()
+    {
+        args.options.manifest_path = Some(args.options.module_dir.clone());
+        args.options.module_dir.pop();
+    }
+    if let Some(target_dir) = &mut args.options.target_dir {
+        *target_dir = resolve_path(&invocation_dir, target_dir);
+    }
+    if let Some(EmitFile::Dir(dir)) = &mut args.emit_file {
+        *dir = resolve_path(&invocation_dir, dir);
+    }
+    if let Some(adapter) = &mut args.options.adapter {
+        *adapter = resolve_path(&invocation_dir, adapter);
+    }
+    if let Some(command_template) = &mut args.options.command_template {
+        *command_template = resolve_path(&invocation_dir, command_template);
+    }
+    if let Some(target) = &mut args.options.target {
+        if target.ends_with(".json") {
+            *target = resolve_path(&invocation_dir, Path::new(target))
+                .to_string_lossy()
+                .to_string();
+        }
+    }
+    debug_log(format_args!(
+        "resolved module directory `{}` relative to `{}`",
+        args.options.module_dir.display(),
+        invocation_dir.display()
+    ));
+    if let Some(InjectData {
+        source: InjectSource::File(file),
+        ..
+    }) = &mut args.options.inject_data
+    {
+        *file = resolve_path(&invocation_dir, file);
+    }
+    // Tools given as paths rather than names are relative to the invocation, like the module
+    for tool in [&mut args.options.post_process, &mut args.options.linker]
+        .into_iter()
+        .flatten()
+    {
+        if tool.components().count() > 1 {
+            *tool = resolve_path(&invocation_dir, tool);
+        }
+    }
+    Ok(())
+}
+
+/// Gives the expansion of `build_wasm!` for the given arguments, whose paths have already been resolved,
+/// using `build` to build the module unless it is a dry run.
+fn expand_built_wasm(
+    args: Args,
+    optional: bool,
+    build: impl FnOnce(&BuildOptions) -> Result<PathBuf, String>,
+) -> proc_macro2::TokenStream {
+    // Given in place of the module if the build fails, so that only the build error is reported
+    let placeholder = if args.emit_file.is_some() {
+        quote! { "" }
+    } else if args.bindgen {
+        bindgen_value(quote! { &[0u8] }, quote! { "" })
+    } else {
+        quote! {
+            {
+                const BS: &'static [u8] = &[0u8];
+                BS
+            }
+        }
+    };
+
+    // Build errors are either reported at compile time or returned at runtime
+    let fail = |err: String| {
+        if optional {
+            // The placeholder gives the type of the `Ok` variant
+            quote! {
+                if false {
+                    Ok(#placeholder)
+                } else {
+                    Err(#err)
+                }
+            }
+        } else {
+            quote! {
+                {
+                    compile_error!(#err);
+                    #placeholder
+                }
+            }
+        }
+    };
+
+    // Register rebuild on files changed, including after a failed build of an optional module
+    let mut module_paths = match &args.prebuilt {
+        Some(prebuilt) => vec![prebuilt.to_string_lossy().to_string()],
+        None => module_files(&args.options),
+    };
+    if let Some(config_file) = &args.config_file {
+        module_paths.push(config_file.to_string_lossy().to_string());
+    }
+    let inject_file = match &args.options.inject_data {
+        Some(InjectData {
+            source: InjectSource::File(file),
+            ..
+        }) => Some(file),
+        _ => None,
+    };
+    if let Some(target) = &args.options.target {
+        if target.ends_with(".json") {
+            module_paths.push(target.clone());
+        }
+    }
+    for file in inject_file.into_iter().chain(&args.env_files) {
+        module_paths.push(file.to_string_lossy().to_string());
+    }
+    for tool in [
+        &args.options.post_process,
+        &args.options.linker,
+        &args.options.adapter,
+        &args.options.command_template,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        if tool.is_file() {
+            module_paths.push(tool.to_string_lossy().to_string());
+        }
+    }
+
+    // The imports of the module, given as a constant with `emit_imports`
+    let mut imports = Vec::new();
+    // The WIT world of the component, given as constants with `emit_world`
+    let mut world = None;
+    // The length of the module in bytes, given as a constant with `emit_len`
+    let mut len = 0u64;
+    // The limits of the module's memory, given as constants with `emit_memory_info`
+    let mut memory = None;
+
+    if args.prebuilt.is_none() {
+        for warning in build_warnings(&args.options) {
+            emit_note(&warning);
+        }
+    }
+
+    let value = if args.dry_run {
+        match build_command(&args.options) {
+            Ok(command) => {
+                emit_note(&format!(
+                    "dry run of `build_wasm`, which would run `{command:?}`"
+                ));
+                let value = if args.bindgen {
+                    bindgen_value(quote! { &[] }, quote! { "" })
+                } else {
+                    quote! {
+                        {
+                            const BS: &'static [u8] = &[];
+                            BS
+                        }
+                    }
+                };
+                if optional {
+                    quote! { Ok::<_, &'static str>(#value) }
+                } else {
+                    value
+                }
+            }
+            Err(err) => fail(err),
+        }
+    } else if args.is_skipped() {
+        let value = if args.emit_file.is_some() {
+            quote! { "" }
+        } else if args.bindgen {
+            bindgen_value(quote! { &[] }, quote! { "" })
+        } else {
+            quote! {
+                {
+                    const BS: &'static [u8] = &[];
+                    BS
+                }
+            }
+        };
+        // The macro can't see the host's profile, so only the compiler can check that this isn't a release build
+        let message = format!(
+            "module `{}` was skipped by `{INCLUDE_WASM_RS_SKIP}` in a build without `debug_assertions`",
+            args.options.module_dir.display()
+        );
+        let value = quote! {
+            {
+                const _: () = ::core::assert!(::core::cfg!(debug_assertions), "{}", #message);
+                #value
+            }
+        };
+        let value = if optional {
+            quote! { Ok::<_, &'static str>(#value) }
+        } else {
+            value
+        };
+        tracked_expr(value, &module_paths, &args.host_env_vars)
+    } else {
+        // Build
+        let result = build(&args.options).and_then(|bytes_path| {
+            if args.emit_imports {
+                imports = read_imports(&bytes_path)?;
+            }
+            if args.emit_world {
+                world = Some(read_world(&bytes_path)?);
+            }
+            if args.emit_memory_info {
+                memory = read_memory(&bytes_path)?;
+            }
+            let file_len = |path: &Path| {
+                std::fs::metadata(path)
+                    .map(|metadata| metadata.len())
+                    .map_err(|e| format!("failed to read module `{}`: {e}", path.display()))
+            };
+            if args.emit_len {
+                len = file_len(&bytes_path)?;
+            }
+            let module_name = args
+                .prebuilt
+                .as_ref()
+                .unwrap_or(&args.options.module_dir)
+                .display();
+            // Checked against the bytes that are included, after any processing
+            let check_digest = |bytes_path: &Path| match &args.expect_sha256 {
+                Some(expected) => check_sha256(bytes_path, expected),
+                None => Ok(()),
+            };
+            if args.bindgen {
+                let (bindgen_path, js_path) = run_bindgen(&bytes_path, &args.options.module_dir)?;
+                check_digest(&bindgen_path)?;
+                if args.report_size {
+                    emit_note(&format!(
+                        "module `{}` is {} bytes, or {} bytes after `wasm-bindgen`",
+                        module_name,
+                        file_len(&bytes_path)?,
+                        file_len(&bindgen_path)?,
+                    ));
+                }
+                let bytes_path = bindgen_path;
+                return Ok((bytes_path, Some(js_path)));
+            }
+            check_digest(&bytes_path)?;
+            if args.report_size {
+                emit_note(&format!(
+                    "module `{}` is {} bytes",
+                    module_name,
+                    file_len(&bytes_path)?,
+                ));
+            }
+            match &args.emit_file {
+                Some(emit_file) => Ok((copy_to_emit_dir(&bytes_path, emit_file)?, None)),
+                None => Ok((bytes_path, None)),
+            }
+        });
+
+        // Either refers to the built files, for the compiler to read later, or embeds their contents now
+        let result = result.and_then(|(bytes_path, js_path)| {
+            let read_err = |path: &Path, e: std::io::Error| {
+                format!("failed to read `{}`: {e}", path.display())
+            };
+            let bytes = |path: &Path| -> Result<proc_macro2::TokenStream, String> {
+                if args.embed_inline {
+                    let bytes = std::fs::read(path).map_err(|e| read_err(path, e))?;
+                    let bytes = proc_macro2::Literal::byte_string(&bytes);
+                    Ok(quote! { #bytes })
+                } else {
+                    let path = path.to_string_lossy().to_string();
+                    Ok(quote! { include_bytes!(#path) })
+                }
+            };
+            if let Some(js_path) = js_path {
+                let js = if args.embed_inline {
+                    let js =
+                        std::fs::read_to_string(&js_path).map_err(|e| read_err(&js_path, e))?;
+                    quote! { #js }
+                } else {
+                    let js_path = js_path.to_string_lossy().to_string();
+                    quote! { include_str!(#js_path) }
+                };
+                return Ok(bindgen_value(bytes(&bytes_path)?, js));
+            }
+            if args.emit_file.is_some() {
+                let bytes_path = bytes_path.to_string_lossy().to_string();
+                return Ok(quote! { #bytes_path });
+            }
+            let bytes = bytes(&bytes_path)?;
+            if args.sized {
+                Ok(bytes)
+            } else {
+                Ok(quote! { #bytes as &'static [u8] })
+            }
+        });
+
+        match result {
+            Ok(value) => {
+                let value = if optional {
+                    quote! { Ok::<_, &'static str>(#value) }
+                } else {
+                    value
+                };
+                tracked_expr(value, &module_paths, &args.host_env_vars)
+            }
+            Err(err) if optional => tracked_expr(fail(err), &module_paths, &args.host_env_vars),
+            Err(err) => fail(err),
+        }
     };
 
-    // Check only one output to avoid hidden bugs
-    if let Some(Ok(_)) = glob_paths.next() {
-        return Err(format!("multiple output files matching `{}` were found - this may be because you recently changed the name of your module; try deleting the folder `{}` and rebuilding", glob.display(), root_output.display()));
+    // Output
+    if !args.emits_items() {
+        return value;
     }
+    let module_ty = if args.emit_file.is_some() {
+        quote! { &'static str }
+    } else {
+        quote! { &'static [u8] }
+    };
+    let mut items = quote! {
+        pub const MODULE: #module_ty = #value;
+    };
+    if args.emit_imports {
+        let (import_modules, import_names): (Vec<String>, Vec<String>) =
+            imports.into_iter().unzip();
+        items.extend(quote! {
+            pub const IMPORTS: &[(&str, &str)] = &[#((#import_modules, #import_names)),*];
+        });
+    }
+    if args.emit_world {
+        // A dry run gives an empty world
+        let (wit, exports, imports) = world
+            .map(|world| (world.wit, world.exports, world.imports))
+            .unwrap_or_default();
+        items.extend(quote! {
+            pub const WORLD: &str = #wit;
+            pub const EXPORTS: &[&str] = &[#(#exports),*];
+            pub const IMPORTS: &[&str] = &[#(#imports),*];
+        });
+    }
+    if args.emit_memory_info {
+        // A module without a memory, or a dry run, needs no memory at all
+        let (min_pages, max_pages) =
+            memory.map_or((0, Some(0)), |memory| (memory.min_pages, memory.max_pages));
+        let pages = |pages: u64| match u32::try_from(pages) {
+            Ok(pages) => quote! { #pages },
+            Err(_) => {
+                let err = format!("memory of {pages} pages doesn't fit in a `u32`");
+                quote! { compile_error!(#err) }
+            }
+        };
+        let min_pages = pages(min_pages);
+        let max_pages = match max_pages {
+            Some(max_pages) => {
+                let max_pages = pages(max_pages);
+                quote! { Some(#max_pages) }
+            }
+            None => quote! { None },
+        };
+        items.extend(quote! {
+            pub const MEMORY_MIN_PAGES: u32 = #min_pages;
+            pub const MEMORY_MAX_PAGES: Option<u32> = #max_pages;
+        });
+    }
+    if args.emit_len {
+        let len = proc_macro2::Literal::u64_unsuffixed(len);
+        items.extend(quote! {
+            pub const LEN: usize = #len;
+        });
+    }
+    items
+}
 
-    drop(lock);
-
-    Ok(output)
+/// The arguments of `build_wasm_many!`.
+struct ManyArgs {
+    modules: Vec<Args>,
+    jobs: Option<usize>,
 }
 
-fn all_module_files(path: PathBuf) -> Vec<String> {
-    let glob_paths = glob::glob(
-        path.as_os_str()
-            .to_str()
-            .expect("output path should be unicode compliant"),
-    )
-    .expect("glob should be valid");
+impl syn::parse::Parse for ManyArgs {
+    fn parse(input: ParseStream) -> syn::parse::Result<Self> {
+        let mut modules = None;
+        let mut jobs = None;
 
-    glob_paths
-        .into_iter()
-        .filter_map(|path| {
-            let path = path.ok()?;
-            if !path.is_file() {
-                None
-            } else {
-                Some(path.to_string_lossy().to_string())
+        while !input.is_empty() {
+            let name = input.parse::<syn::Ident>()?;
+            input.parse::<syn::Token![:]>()?;
+            match name.to_string().as_str() {
+                "jobs" => {
+                    // Positive integer
+                    let value = input.parse::<syn::LitInt>()?;
+                    jobs = match value.base10_parse::<usize>() {
+                        Ok(jobs) if jobs > 0 => Some(jobs),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.span(),
+                                "expected a positive number of jobs",
+                            ))
+                        }
+                    };
+                }
+                "modules" => {
+                    // Array of module arguments, each either a path or a braced dict of options
+                    let content;
+                    syn::bracketed!(content in input);
+                    let mut parsed = Vec::new();
+                    while !content.is_empty() {
+                        let args = if content.peek(syn::token::Brace) {
+                            let options;
+                            syn::braced!(options in content);
+                            options.parse::<Args>()?
+                        } else {
+                            content.parse::<Args>()?
+                        };
+                        if args.emits_items() || args.prebuilt.is_some() {
+                            return Err(syn::Error::new(
+                                name.span(),
+                                "`emit_imports`, `emit_world`, `emit_len`, `emit_memory_info` and `prebuilt` cannot be used with `build_wasm_many`",
+                            ));
+                        }
+                        parsed.push(args);
+                        if !content.is_empty() {
+                            content.parse::<syn::Token![,]>()?;
+                        }
+                    }
+                    modules = Some(parsed);
+                }
+                _ => return Err(syn::Error::new(name.span(), "unexpected key")),
             }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+
+        match modules {
+            Some(modules) => Ok(Self { modules, jobs }),
+            None => Err(syn::Error::new(input.span(), "expected `modules`")),
+        }
+    }
+}
+
+/// Builds many Rust WebAssembly modules at compile time, in parallel, giving a tuple of what
+/// [`build_wasm!`] would give for each.
+///
+/// Each module is given either as a path or as a braced set of the arguments taken by `build_wasm!`,
+/// other than `emit_imports`, `emit_world`, `emit_len`, `emit_memory_info` and `prebuilt`. Every module is built even if some fail, and each failure is reported.
+///
+/// # Usage
+///
+/// ```ignore
+/// let (first, second) = build_wasm_many! {
+///     // The number of modules to build at once. Defaults to the available parallelism.
+///     jobs: 4,
+///     modules: [
+///         "relative/path/to/first",
+///         { path: "relative/path/to/second", release: true },
+///     ],
+/// };
+/// ```
+#[proc_macro]
+pub fn build_wasm_many(args: TokenStream) -> TokenStream {
+    // Parse args
+    let ManyArgs { modules, jobs } = parse_macro_input!(args as ManyArgs);
+
+    expand_build_wasm_many(modules, jobs).into()
+}
+
+/// Builds the given modules in parallel, giving a tuple of the expansion of `build_wasm!` for each.
+fn expand_build_wasm_many(mut modules: Vec<Args>, jobs: Option<usize>) -> proc_macro2::TokenStream {
+    for args in &mut modules {
+        if let Err(err) = resolve_paths(args) {
+            return syn::Error::new(proc_macro2::Span::call_site(), err).to_compile_error();
+        }
+    }
+
+    // Dry runs and skipped modules aren't built
+    let to_build = modules
+        .iter()
+        .filter(|args| !args.dry_run && !args.is_skipped())
+        .map(|args| args.options.clone())
+        .collect::<Vec<_>>();
+    let mut results = include_wasm_rs_build::build_many(&to_build, jobs).into_iter();
+
+    let values = modules.into_iter().map(|args| {
+        let result = if args.dry_run || args.is_skipped() {
+            None
+        } else {
+            results.next()
+        };
+        expand_built_wasm(args, false, |_| {
+            result.expect("every module that isn't a dry run or skipped should have been built")
         })
-        .collect()
+    });
+    quote! {
+        (#(#values,)*)
+    }
 }
 
-/// Invokes `cargo build` at compile time on another module, replacing this macro invocation
-/// with the bytes contained in the output `.wasm` file.
+/// The arguments of `build_wasm_profiles!`.
+struct ProfilesArgs {
+    args: Args,
+    /// Whether each profile is `release`, in the order given.
+    profiles: Vec<bool>,
+}
+
+impl syn::parse::Parse for ProfilesArgs {
+    fn parse(input: ParseStream) -> syn::parse::Result<Self> {
+        let dict =
+            syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::parse_terminated_with(
+                input,
+                parse_option,
+            )?;
+
+        // Everything other than `profiles` is given to each build
+        let mut profiles = None;
+        let mut rest = syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::new();
+        for value in dict {
+            match option_name(&value).as_str() {
+                "profiles" => {
+                    // Array of `debug` or `release` identifiers, each at most once
+                    let syn::Expr::Array(array) = degroup_expr(value.expr) else {
+                        return Err(syn::Error::new(
+                            value.member.span(),
+                            "expected an array of profiles",
+                        ));
+                    };
+                    let mut parsed = Vec::new();
+                    for profile in array.elems {
+                        let release = match &profile {
+                            syn::Expr::Path(path) if path.path.is_ident("debug") => false,
+                            syn::Expr::Path(path) if path.path.is_ident("release") => true,
+                            _ => {
+                                return Err(syn::Error::new(
+                                    profile.span(),
+                                    "expected `debug` or `release`",
+                                ))
+                            }
+                        };
+                        if parsed.contains(&release) {
+                            return Err(syn::Error::new(profile.span(), "duplicate profile"));
+                        }
+                        parsed.push(release);
+                    }
+                    if parsed.is_empty() {
+                        return Err(syn::Error::new(
+                            array.bracket_token.span.join(),
+                            "expected at least one profile",
+                        ));
+                    }
+                    profiles = Some(parsed);
+                }
+                "release" => {
+                    return Err(syn::Error::new(
+                        value.member.span(),
+                        "`release` cannot be used with `build_wasm_profiles`, which builds each of `profiles`",
+                    ))
+                }
+                _ => rest.push(value),
+            }
+        }
+
+        let args = syn::parse2::<Args>(rest.into_token_stream())?;
+        if args.emits_items() || args.prebuilt.is_some() {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "`emit_imports`, `emit_world`, `emit_len`, `emit_memory_info` and `prebuilt` cannot be used with `build_wasm_profiles`",
+            ));
+        }
+        match profiles {
+            Some(profiles) => Ok(Self { args, profiles }),
+            None => Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "missing required `profiles` option",
+            )),
+        }
+    }
+}
+
+/// Builds a Rust WebAssembly module at compile time in each of the given profiles, in parallel, giving a tuple
+/// of what [`build_wasm!`] would give for the module built in each profile, in the order given.
+///
+/// Takes the same arguments as `build_wasm!`, other than `release`, `emit_imports`, `emit_world`, `emit_len`,
+/// `emit_memory_info` and `prebuilt`. Each profile is built in its own target directory.
 ///
 /// # Usage
 ///
 /// ```ignore
-/// let module = build_wasm!("relative/path/to/module");
+/// let (debug, release): (&'static [u8], &'static [u8]) = build_wasm_profiles! {
+///     path: "relative/path/to/module",
+///     // Each of `debug` or `release`, at most once.
+///     profiles: [debug, release],
+/// };
 /// ```
+#[proc_macro]
+pub fn build_wasm_profiles(args: TokenStream) -> TokenStream {
+    // Parse args
+    let ProfilesArgs { args, profiles } = parse_macro_input!(args as ProfilesArgs);
+
+    let modules = profiles
+        .into_iter()
+        .map(|release| {
+            let mut args = args.clone();
+            args.options.release = release;
+            args
+        })
+        .collect();
+    expand_build_wasm_many(modules, None).into()
+}
+
+/// The arguments of `build_wasm_targets!`.
+struct TargetsArgs {
+    /// The name of the field holding each target's module, and the arguments to build it with.
+    targets: Vec<(syn::Ident, Args)>,
+}
+
+impl syn::parse::Parse for TargetsArgs {
+    fn parse(input: ParseStream) -> syn::parse::Result<Self> {
+        // Everything other than `targets` is given to each build
+        let mut targets = None;
+        let mut rest = syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::new();
+        while !input.is_empty() {
+            // Bare braced dicts, e.g. of `inject_data`, aren't expressions
+            if input.peek2(syn::Token![:]) && input.peek3(syn::token::Brace) {
+                rest.push(parse_option(input)?);
+                if !input.is_empty() {
+                    input.parse::<syn::Token![,]>()?;
+                }
+                continue;
+            }
+            let name = input.parse::<syn::Ident>()?;
+            input.parse::<syn::Token![:]>()?;
+            match name.to_string().as_str() {
+                "targets" => {
+                    // Array of braced dicts, each giving a `triple`, and optionally a `name` and `features`
+                    let content;
+                    syn::bracketed!(content in input);
+                    let mut parsed = Vec::new();
+                    while !content.is_empty() {
+                        let options;
+                        let brace_token = syn::braced!(options in content);
+                        let dict = syn::punctuated::Punctuated::<syn::FieldValue, syn::Token![,]>::parse_terminated(
+                            &options,
+                        )?;
+                        let mut field = None;
+                        let mut triple = None;
+                        let mut features = None;
+                        for value in dict {
+                            match option_name(&value).as_str() {
+                                "name" => {
+                                    // Identifier
+                                    field = match degroup_expr(value.expr.clone()) {
+                                        syn::Expr::Path(path) if path.path.get_ident().is_some() => {
+                                            path.path.get_ident().cloned()
+                                        }
+                                        _ => {
+                                            return Err(syn::Error::new(
+                                                value.expr.span(),
+                                                "expected identifier",
+                                            ))
+                                        }
+                                    };
+                                }
+                                "triple" => {
+                                    // String, validated as `target` by `Args`
+                                    triple = match expect_lit(&value.expr, "literal string")? {
+                                        syn::Lit::Str(triple) => Some(triple.clone()),
+                                        _ => {
+                                            return Err(syn::Error::new(
+                                                value.expr.span(),
+                                                "expected literal string",
+                                            ))
+                                        }
+                                    };
+                                }
+                                "features" => {
+                                    // Merged with any `features` given for every target
+                                    features = Some(value.expr);
+                                }
+                                option => {
+                                    return Err(syn::Error::new(
+                                        value.member.span(),
+                                        format!("unknown target option `{option}`, expected `name`, `triple` or `features`"),
+                                    ))
+                                }
+                            }
+                        }
+
+                        let Some(triple) = triple else {
+                            return Err(syn::Error::new(
+                                brace_token.span.join(),
+                                "missing required `triple` option",
+                            ));
+                        };
+                        // Web and WASI targets have default names, but others must be named
+                        let field = match field {
+                            Some(field) => field,
+                            None if triple.value().contains("-wasi") => {
+                                syn::Ident::new("wasi", triple.span())
+                            }
+                            None if triple.value().ends_with("-unknown-unknown") => {
+                                syn::Ident::new("web", triple.span())
+                            }
+                            None => {
+                                return Err(syn::Error::new(
+                                    triple.span(),
+                                    "expected a `name` for the field holding the module of this target",
+                                ))
+                            }
+                        };
+                        if parsed.iter().any(|(other, _, _)| other == &field) {
+                            return Err(syn::Error::new(
+                                field.span(),
+                                format!("duplicate target name `{field}`, give each target a different `name`"),
+                            ));
+                        }
+                        parsed.push((field, triple, features));
+
+                        if !content.is_empty() {
+                            content.parse::<syn::Token![,]>()?;
+                        }
+                    }
+                    if parsed.is_empty() {
+                        return Err(syn::Error::new(
+                            name.span(),
+                            "expected at least one target",
+                        ));
+                    }
+                    targets = Some(parsed);
+                }
+                "target" => {
+                    return Err(syn::Error::new(
+                        name.span(),
+                        "`target` cannot be used with `build_wasm_targets`, which builds each of `targets`",
+                    ))
+                }
+                _ => {
+                    let value = input.parse::<syn::Expr>()?;
+                    rest.push(syn::parse_quote! { #name: #value });
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+
+        let Some(targets) = targets else {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "missing required `targets` option",
+            ));
+        };
+        let targets = targets
+            .into_iter()
+            .map(|(field, triple, features)| {
+                let mut dict = rest.clone();
+                dict.push(syn::parse_quote! { target: #triple });
+                if let Some(features) = features {
+                    dict.push(syn::parse_quote! { features: #features });
+                }
+                let args = syn::parse2::<Args>(dict.into_token_stream())?;
+                if args.emits_items() || args.prebuilt.is_some() {
+                    return Err(syn::Error::new(
+                        proc_macro2::Span::call_site(),
+                        "`emit_imports`, `emit_world`, `emit_len`, `emit_memory_info` and `prebuilt` cannot be used with `build_wasm_targets`",
+                    ));
+                }
+                Ok((field, args))
+            })
+            .collect::<syn::parse::Result<_>>()?;
+        Ok(Self { targets })
+    }
+}
+
+/// Builds a Rust WebAssembly module at compile time for each of the given targets, in parallel, giving a struct
+/// with a field holding what [`build_wasm!`] would give for the module built for each target.
 ///
-/// # Arguments
+/// Takes the same arguments as `build_wasm!`, other than `target`, `emit_imports`, `emit_world`, `emit_len`,
+/// `emit_memory_info` and `prebuilt`. Each target gives its `triple`, and optionally `features`, which are merged
+/// with any given for every target. Its field is named by `name`, which defaults to `web` for `*-unknown-unknown`
+/// targets and `wasi` for WASI targets. Each target is built in its own target directory.
 ///
-/// This macro can take a number of additional arguments to control how the WebAssembly should be generated.
-/// These options are passed to `cargo build`:
+/// # Usage
 ///
 /// ```ignore
-/// let module = build_wasm!{
+/// let modules = build_wasm_targets! {
 ///     path: "relative/path/to/module",
-///     features: [
-///         atomics, // Controls if the `atomics` proposal is enabled
-///         bulk_memory, // Controls if the `bulk-memory` proposal is enabled
-///         mutable_globals, // Controls if the `mutable-globals` proposal is enabled
+///     features: [mutable_globals],
+///     targets: [
+///         { triple: "wasm32-unknown-unknown", features: [bulk_memory] },
+///         { triple: "wasm32-wasip1" },
 ///     ],
-///     // Allows additional environment variables to be set while compiling the module.
-///     env: Env {
-///         FOO: "bar",
-///         BAX: 7,
-///     },
-///     // Controls if the module should be built in debug or release mode.
-///     release: true
 /// };
+/// let (web, wasi): (&'static [u8], &'static [u8]) = (modules.web, modules.wasi);
 /// ```
 #[proc_macro]
-pub fn build_wasm(args: TokenStream) -> TokenStream {
+pub fn build_wasm_targets(args: TokenStream) -> TokenStream {
     // Parse args
-    let mut args = parse_macro_input!(args as Args);
+    let TargetsArgs { targets } = parse_macro_input!(args as TargetsArgs);
 
-    #[cfg(not(feature = "proc_macro_span"))]
-    let invocation_file = {
-        let root =
-            std::env::var("CARGO_MANIFEST_DIR").expect("proc macros should be run using cargo");
-        find_me(&root, &format!("\"{}\"", args.module_dir.to_string_lossy()))
-    };
-    #[cfg(feature = "proc_macro_span")]
-    let invocation_file = proc_macro::Span::call_site().source_file().path();
-    let invocation_file = invocation_file
-        .parent()
-        .unwrap()
-        .to_path_buf()
-        .canonicalize()
-        .unwrap();
-    args.module_dir = invocation_file.join(args.module_dir);
+    let (fields, modules): (Vec<_>, Vec<_>) = targets.into_iter().unzip();
+    let values = expand_build_wasm_many(modules, None);
+    let params = (0..fields.len())
+        .map(|i| quote::format_ident!("T{i}"))
+        .collect::<Vec<_>>();
+    quote! {
+        {
+            #[derive(Clone, Copy, Debug)]
+            struct TargetModules<#(#params),*> {
+                #(#fields: #params),*
+            }
+            let (#(#fields,)*) = #values;
+            TargetModules { #(#fields),* }
+        }
+    }
+    .into()
+}
+
+/// Assembles a WebAssembly text file at compile time, replacing this macro invocation
+/// with the bytes of the assembled module.
+///
+/// Requires the `wat` feature.
+///
+/// # Usage
+///
+/// ```ignore
+/// let module = build_wat!("relative/path/to/module.wat");
+/// ```
+#[cfg(feature = "wat")]
+#[proc_macro]
+pub fn build_wat(args: TokenStream) -> TokenStream {
+    // Parse args
+    let path = parse_macro_input!(args as syn::LitStr);
+    let path = PathBuf::from(path.value());
 
-    // Build
-    let result = do_build_wasm(&args);
+    // Assemble
+    let result = invocation_dir(&path).and_then(|invocation_dir| {
+        let wat_path = invocation_dir.join(path);
+        let bytes = wat::parse_file(&wat_path).map_err(|err| {
+            format!(
+                "failed to assemble `{}`: \n{}",
+                wat_path.display(),
+                err.to_string().replace('\n', "\n\t")
+            )
+        })?;
+        Ok((wat_path, bytes))
+    });
 
     // Output
     match result {
-        Ok(bytes_path) => {
-            let bytes_path = bytes_path.to_string_lossy().to_string();
-            // Register rebuild on files changed
-            let module_paths = all_module_files(args.module_dir);
+        Ok((wat_path, bytes)) => {
+            let bytes = proc_macro2::Literal::byte_string(&bytes);
+            let wat_path = wat_path.to_string_lossy().to_string();
 
-            quote! {
-                {
-                    #(
-                        let _ = include_str!(#module_paths);
-                    )*
-                    include_bytes!(#bytes_path) as &'static [u8]
-                }
-            }
+            tracked_expr(quote! { #bytes as &'static [u8] }, &[wat_path], &[])
         }
         Err(err) => quote! {
             {
@@ -567,3 +2896,315 @@ pub fn build_wasm(args: TokenStream) -> TokenStream {
     }
     .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives the message of the error from parsing invalid macro arguments.
+    fn parse_error(args: &str) -> String {
+        match syn::parse_str::<Args>(args) {
+            Ok(_) => panic!("`{args}` should fail to parse"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn lto_and_codegen_units_are_parsed() {
+        let args =
+            syn::parse_str::<Args>(r#"path: "module", lto: "thin", codegen_units: 1"#).unwrap();
+        assert_eq!(args.options.lto.as_deref(), Some("thin"));
+        assert_eq!(args.options.codegen_units, Some(1));
+
+        let args = syn::parse_str::<Args>(r#"path: "module", lto: true"#).unwrap();
+        assert_eq!(args.options.lto.as_deref(), Some("fat"));
+        let args = syn::parse_str::<Args>(r#"path: "module", lto: false"#).unwrap();
+        assert_eq!(args.options.lto.as_deref(), Some("off"));
+
+        assert_eq!(
+            parse_error(r#"path: "module", lto: "full""#),
+            "expected one of `\"fat\"`, `\"thin\"` or `\"off\"`"
+        );
+        assert_eq!(
+            parse_error(r#"path: "module", codegen_units: 0"#),
+            "expected a positive number of codegen units"
+        );
+    }
+
+    #[test]
+    fn structured_env_values_are_serialized_to_json() {
+        let json = |expr: &str| expr_to_json(&syn::parse_str(expr).unwrap());
+
+        assert_eq!(
+            json(r#""a \"quoted\"\nline""#).unwrap(),
+            r#""a \"quoted\"\nline""#
+        );
+        assert_eq!(
+            json("[1, -2, 3.5, -0.25, true]").unwrap(),
+            "[1,-2,3.5,-0.25,true]"
+        );
+        assert_eq!(
+            json(r#"Config { name: "module", sizes: [1, 2], nested: Inner { on: false } }"#)
+                .unwrap(),
+            r#"{"name":"module","sizes":[1,2],"nested":{"on":false}}"#
+        );
+        assert_eq!(json("[]").unwrap(), "[]");
+
+        assert_eq!(
+            json("some_ident").unwrap_err().to_string(),
+            "expected a value representable as JSON, found `some_ident`"
+        );
+        assert!(json("Config { ..Default::default() }").is_err());
+        assert!(json("Tuple { 0: 1 }").is_err());
+        assert!(json("b'x'").is_err());
+    }
+
+    #[test]
+    fn z_flags_are_parsed() {
+        let args =
+            syn::parse_str::<Args>(r#"path: "module", z_flags: ["wasm-c-abi=spec"]"#).unwrap();
+        assert_eq!(args.options.z_flags, ["wasm-c-abi=spec"]);
+
+        assert_eq!(
+            parse_error(r#"path: "module", z_flags: [""]"#),
+            "expected a non-empty flag"
+        );
+        assert_eq!(
+            parse_error(r#"path: "module", z_flags: ["a b"]"#),
+            "`-Z` flags may not contain whitespace"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn absolute_unix_paths_are_used_as_given() {
+        let invocation_dir = Path::new("/home/user/host/src");
+        assert_eq!(
+            resolve_path(invocation_dir, Path::new("/opt/module")),
+            Path::new("/opt/module")
+        );
+        assert_eq!(
+            resolve_path(invocation_dir, Path::new("../module")),
+            Path::new("/home/user/host/src/../module")
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn absolute_windows_paths_are_used_as_given() {
+        let invocation_dir = Path::new(r"C:\Users\user\host\src");
+        assert_eq!(
+            resolve_path(invocation_dir, Path::new(r"D:\module")),
+            Path::new(r"D:\module")
+        );
+        assert_eq!(
+            resolve_path(invocation_dir, Path::new(r"\\server\share\module")),
+            Path::new(r"\\server\share\module")
+        );
+        // Relative paths are still resolved against the invoking file's directory
+        assert_eq!(
+            resolve_path(invocation_dir, Path::new(r"..\module")),
+            Path::new(r"C:\Users\user\host\src\..\module")
+        );
+    }
+
+    #[test]
+    fn multivalue_and_tail_call_are_parsed() {
+        let args =
+            syn::parse_str::<Args>(r#"path: "module", features: [multivalue, tail_call]"#).unwrap();
+        assert!(args.options.features.multivalue);
+        assert!(args.options.features.tail_call);
+    }
+
+    #[test]
+    fn base64_matches_rfc_4648_examples() {
+        for (bytes, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(bytes.as_bytes()), encoded);
+        }
+        assert_eq!(base64(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    #[cfg(not(feature = "tracked_path"))]
+    fn binary_module_files_are_tracked_as_bytes() {
+        let guest = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(guest.path().join("src")).unwrap();
+        std::fs::write(
+            guest.path().join("Cargo.toml"),
+            "[package]\nname = \"guest\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        std::fs::write(guest.path().join("src/lib.rs"), "").unwrap();
+        std::fs::write(guest.path().join("src/blob.bin"), [0xff, 0xfe, 0x00, 0x80]).unwrap();
+
+        let files = module_files(&BuildOptions {
+            module_dir: guest.path().to_owned(),
+            ..BuildOptions::default()
+        });
+        assert!(files.iter().any(|file| file.ends_with("blob.bin")));
+
+        let tracked = tracked_expr(quote! { MODULE }, &files, &[]).to_string();
+        assert!(tracked.contains("include_bytes"));
+        assert!(!tracked.contains("include_str"));
+    }
+
+    #[test]
+    fn inject_data_accepts_bare_and_named_dicts() {
+        for args in [
+            r#"path: "module", inject_data: { segment: 1, bytes_from_env: "CONFIG" }"#,
+            r#"path: "module", inject_data: InjectData { segment: 1, bytes_from_env: "CONFIG" }"#,
+        ] {
+            let args = syn::parse_str::<Args>(args).unwrap();
+            let Some(InjectData {
+                segment: 1,
+                source: InjectSource::Env(name),
+            }) = args.options.inject_data
+            else {
+                panic!("expected segment 1 to be injected from an env var");
+            };
+            assert_eq!(name, "CONFIG");
+        }
+
+        let args = syn::parse_str::<TargetsArgs>(
+            r#"path: "module", targets: [{ triple: "wasm32-unknown-unknown" }], inject_data: { segment: 0, bytes_from_file: "config.bin" }"#,
+        )
+        .unwrap();
+        assert!(args.targets[0].1.options.inject_data.is_some());
+
+        assert_eq!(
+            parse_error(
+                r#"path: "module", inject_data: { segment: "config", bytes_from_env: "CONFIG" }"#
+            ),
+            "data segments can't be chosen by name, expected the index of the segment"
+        );
+        assert_eq!(
+            parse_error(
+                r#"path: "module", inject_data: { segment: config, bytes_from_env: "CONFIG" }"#
+            ),
+            "data segments can't be chosen by name, expected the index of the segment"
+        );
+        assert_eq!(
+            parse_error(
+                r#"path: "module", inject_data: Data { segment: 0, bytes_from_env: "CONFIG" }"#
+            ),
+            "expected `{ segment: .., bytes_from_env: .. }`"
+        );
+    }
+
+    #[test]
+    fn host_env_values_stay_out_of_target_dirs() {
+        // Set by cargo for every test, and a path
+        let args = syn::parse_str::<Args>(
+            r#"path: "module", env: Env { DIR: env("CARGO_MANIFEST_DIR") }"#,
+        )
+        .unwrap();
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        assert_eq!(
+            args.options.env_vars,
+            [("DIR".to_owned(), manifest_dir.clone())]
+        );
+
+        let command = build_command(&args.options).unwrap();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let target_dir = &args[args.iter().position(|arg| arg == "--target-dir").unwrap() + 1];
+        // The value is hashed into the last component, rather than adding components of its own
+        let name = Path::new(target_dir).file_name().unwrap().to_str().unwrap();
+        assert!(name.contains("_DIR_"));
+        assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+    }
+
+    #[test]
+    fn config_file_values_are_given_as_options() {
+        // Parses the options of a config file as `config_file_options` gives them
+        let parse = |contents: &str| {
+            let options = contents
+                .parse::<toml::Table>()
+                .unwrap()
+                .iter()
+                .map(|(key, value)| {
+                    let key = syn::Ident::new(key, proc_macro2::Span::call_site());
+                    let value = toml_to_tokens(&key.to_string(), value).unwrap();
+                    syn::parse::Parser::parse2(parse_option, quote! { #key: #value }).unwrap()
+                })
+                .collect::<Vec<syn::FieldValue>>();
+            syn::parse2::<Args>(quote! { path: "module", #(#options),* }).unwrap()
+        };
+
+        let args = parse("embed = \"inline\"");
+        assert!(args.embed_inline);
+
+        let args = parse("[inject_data]\nsegment = 1\nbytes_from_env = \"CONFIG\"");
+        let Some(InjectData {
+            segment: 1,
+            source: InjectSource::Env(name),
+        }) = args.options.inject_data
+        else {
+            panic!("expected segment 1 to be injected from an env var");
+        };
+        assert_eq!(name, "CONFIG");
+
+        let args = parse("[env]\nLOG = \"debug\"");
+        assert_eq!(
+            args.options.env_vars,
+            [("LOG".to_owned(), "debug".to_owned())]
+        );
+
+        let args = parse("release = true\nfeatures = [\"bulk-memory\"]\nopt_level = \"z\"");
+        assert!(args.options.release);
+        assert!(args.options.features.bulk_memory);
+        assert_eq!(args.options.opt_level.as_deref(), Some("z"));
+
+        assert_eq!(
+            toml_to_tokens("embed", &toml::Value::String("in line".to_owned())).unwrap_err(),
+            "expected an identifier, found `in line`"
+        );
+    }
+
+    #[test]
+    fn clean_is_rejected_with_shared_target_dirs() {
+        assert_eq!(
+            parse_error(r#"path: "module", clean: true, target_dir: "target""#),
+            "`clean` cannot be used with `target_dir`"
+        );
+        assert_eq!(
+            parse_error(r#"path: "module", clean: true, share_std: true"#),
+            "`clean` cannot be used with `share_std`"
+        );
+    }
+
+    #[test]
+    fn features_must_be_an_array() {
+        assert_eq!(
+            parse_error(r#"path: "module", features: true"#),
+            "expected array of features"
+        );
+    }
+
+    #[test]
+    fn boolean_options_are_parsed() {
+        let args =
+            syn::parse_str::<Args>(r#"path: "module", release: true, dry_run: false"#).unwrap();
+        assert!(args.options.release);
+        assert!(!args.dry_run);
+
+        assert_eq!(
+            parse_error(r#"path: "module", release: 1"#),
+            "expected boolean"
+        );
+        assert_eq!(
+            parse_error(r#"path: "module", cache: yes"#),
+            "expected boolean"
+        );
+    }
+}
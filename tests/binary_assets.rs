@@ -0,0 +1,8 @@
+use include_wasm_rs::build_wasm;
+
+// The module isn't UTF-8, so tracking it must not use `include_str!`
+#[test]
+fn binary_prebuilt_module_is_tracked() {
+    let module = build_wasm! { prebuilt: "fixtures/binary.wasm" };
+    assert_eq!(module, b"\0asm\x01\0\0\0\0\x03\x01x\xff");
+}
@@ -0,0 +1,12 @@
+use include_wasm_rs::build_wasm;
+
+// The expansion, including any statements tracking the module's files, must be a constant expression.
+// Each path is spelled differently, since the invoking file is found by searching for it.
+static MODULE: &[u8] = build_wasm! { prebuilt: "./fixtures/module.wasm" };
+const MODULE_CONST: &[u8] = build_wasm! { prebuilt: "fixtures/./module.wasm" };
+
+#[test]
+fn modules_can_initialize_statics_and_consts() {
+    assert_eq!(MODULE, b"\0asm\x01\0\0\0");
+    assert_eq!(MODULE_CONST, MODULE);
+}